@@ -0,0 +1,312 @@
+use qif_generator::account::AccountType;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::str::FromStr;
+
+/// GnuCash account types
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum AccountKind {
+    Asset,
+    Bank,
+    Cash,
+    Credit,
+    Liability,
+    Stock,
+    Mutual,
+    Currency,
+    Income,
+    Expense,
+    Equity,
+    Receivable,
+    Payable,
+    Trading,
+    Root,
+}
+
+impl AccountKind {
+    /// Accounts purchases can be paid from
+    pub fn is_source(&self) -> bool {
+        matches!(
+            self,
+            AccountKind::Asset
+                | AccountKind::Bank
+                | AccountKind::Cash
+                | AccountKind::Credit
+                | AccountKind::Liability
+        )
+    }
+
+    /// QIF type for the accounts purchases can be paid from
+    pub fn qif_type(&self) -> Option<AccountType> {
+        match self {
+            AccountKind::Asset => Some(AccountType::AssetAccount),
+            AccountKind::Bank => Some(AccountType::Bank),
+            AccountKind::Cash => Some(AccountType::Cash),
+            AccountKind::Credit => Some(AccountType::CreditCard),
+            AccountKind::Liability => Some(AccountType::LiabilityAccount),
+            AccountKind::Stock | AccountKind::Mutual => Some(AccountType::Investment),
+            _ => None,
+        }
+    }
+
+    /// Accounts used as transaction categories
+    pub fn is_category(&self) -> bool {
+        *self == AccountKind::Expense
+    }
+
+    /// Guess the type by the top level account, as plain-text accounting
+    /// tools do
+    pub fn from_name(name: &str) -> Option<Self> {
+        let root = name.split(':').next().unwrap_or_default();
+        match root.trim().to_lowercase().as_str() {
+            "assets" | "asset" => Some(AccountKind::Asset),
+            "liabilities" | "liability" => Some(AccountKind::Liability),
+            "expenses" | "expense" => Some(AccountKind::Expense),
+            "income" | "revenue" | "revenues" => Some(AccountKind::Income),
+            "equity" => Some(AccountKind::Equity),
+            _ => None,
+        }
+    }
+
+    /// Parse hledger `type:` tag value
+    pub fn from_hledger_type(tag: &str) -> Option<Self> {
+        match tag.trim().to_lowercase().as_str() {
+            "a" | "asset" => Some(AccountKind::Asset),
+            "l" | "liability" => Some(AccountKind::Liability),
+            "e" | "equity" | "v" | "conversion" => Some(AccountKind::Equity),
+            "r" | "revenue" => Some(AccountKind::Income),
+            "x" | "expense" => Some(AccountKind::Expense),
+            "c" | "cash" => Some(AccountKind::Cash),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for AccountKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_uppercase().as_str() {
+            "ASSET" => Ok(AccountKind::Asset),
+            "BANK" => Ok(AccountKind::Bank),
+            "CASH" => Ok(AccountKind::Cash),
+            "CREDIT" => Ok(AccountKind::Credit),
+            "LIABILITY" => Ok(AccountKind::Liability),
+            "STOCK" => Ok(AccountKind::Stock),
+            "MUTUAL" => Ok(AccountKind::Mutual),
+            "CURRENCY" => Ok(AccountKind::Currency),
+            "INCOME" => Ok(AccountKind::Income),
+            "EXPENSE" => Ok(AccountKind::Expense),
+            "EQUITY" => Ok(AccountKind::Equity),
+            "RECEIVABLE" => Ok(AccountKind::Receivable),
+            "PAYABLE" => Ok(AccountKind::Payable),
+            "TRADING" => Ok(AccountKind::Trading),
+            "ROOT" => Ok(AccountKind::Root),
+            other => Err(format!("Unknown account type {}", other)),
+        }
+    }
+}
+
+impl fmt::Display for AccountKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", format!("{:?}", self).to_uppercase())
+    }
+}
+
+/// Single account of the imported account tree
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AccountInfo {
+    pub kind: AccountKind,
+    /// Full colon-separated name, e.g. "Expenses:Food:Bread"
+    pub name: String,
+    /// Full name of the parent account
+    pub parent: Option<String>,
+    pub hidden: bool,
+    /// Placeholder accounts only group other accounts
+    pub placeholder: bool,
+}
+
+impl AccountInfo {
+    pub fn new(kind: AccountKind, name: &str) -> Self {
+        AccountInfo {
+            kind,
+            name: name.to_string(),
+            parent: name.rsplit_once(':').map(|(parent, _)| parent.to_string()),
+            hidden: false,
+            placeholder: false,
+        }
+    }
+
+    /// Whether transactions can be posted to the account
+    pub fn is_postable(&self) -> bool {
+        !self.hidden && !self.placeholder
+    }
+}
+
+/// Step of hierarchical account selection
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// Parent account leading to deeper accounts
+    Group(String),
+    /// Account to be chosen
+    Leaf(String),
+}
+
+/// Accounts directly under `parent` and groups leading to the deeper ones.
+/// Empty `parent` means the top level.
+pub fn children<'a, I>(names: I, parent: &str) -> Vec<Level>
+where
+    I: IntoIterator<Item = &'a String>,
+{
+    let mut result = BTreeSet::new();
+    for name in names {
+        let rest = if parent.is_empty() {
+            Some(name.as_str())
+        } else {
+            name.strip_prefix(parent)
+                .and_then(|rest| rest.strip_prefix(':'))
+        };
+        match rest.map(|rest| rest.split_once(':')) {
+            Some(Some((group, _))) => {
+                let group = if parent.is_empty() {
+                    group.to_string()
+                } else {
+                    format!("{}:{}", parent, group)
+                };
+                result.insert(Level::Group(group));
+            }
+            Some(None) => {
+                result.insert(Level::Leaf(name.clone()));
+            }
+            None => (),
+        }
+    }
+    result.into_iter().collect()
+}
+
+/// Typed accounts by full name
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct AccountTree {
+    accounts: BTreeMap<String, AccountInfo>,
+}
+
+impl AccountTree {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn insert(&mut self, info: AccountInfo) {
+        self.accounts.insert(info.name.clone(), info);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&AccountInfo> {
+        self.accounts.get(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &AccountInfo> {
+        self.accounts.values()
+    }
+
+    /// Accounts to be used as transaction categories, including placeholders
+    /// and hidden ones
+    pub fn categories(&self) -> impl Iterator<Item = &AccountInfo> {
+        self.iter().filter(|a| a.kind.is_category())
+    }
+
+    /// Accounts purchases can be paid from
+    pub fn sources(&self) -> impl Iterator<Item = &AccountInfo> {
+        self.iter().filter(|a| a.kind.is_source())
+    }
+}
+
+impl FromIterator<AccountInfo> for AccountTree {
+    fn from_iter<I: IntoIterator<Item = AccountInfo>>(iter: I) -> Self {
+        let mut tree = AccountTree::new();
+        for info in iter {
+            tree.insert(info);
+        }
+        tree
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kind() {
+        assert_eq!("expense".parse(), Ok(AccountKind::Expense));
+        assert!("SAVINGS".parse::<AccountKind>().is_err());
+        assert_eq!(AccountKind::Credit.to_string(), "CREDIT");
+        assert!(AccountKind::Credit.is_source());
+        assert!(!AccountKind::Income.is_source());
+        assert_eq!(
+            AccountKind::Credit.qif_type(),
+            Some(AccountType::CreditCard)
+        );
+        assert_eq!(AccountKind::Expense.qif_type(), None);
+        assert_eq!(
+            AccountKind::from_name("Revenues:Salary"),
+            Some(AccountKind::Income)
+        );
+        assert_eq!(AccountKind::from_name("Budget:Food"), None);
+        assert_eq!(AccountKind::from_hledger_type("C"), Some(AccountKind::Cash));
+    }
+
+    #[test]
+    fn test_tree() {
+        let info = AccountInfo::new(AccountKind::Expense, "Expenses:Food:Bread");
+        assert_eq!(info.parent.as_deref(), Some("Expenses:Food"));
+        assert_eq!(AccountInfo::new(AccountKind::Asset, "Assets").parent, None);
+
+        let tree: AccountTree = [
+            info,
+            AccountInfo::new(AccountKind::Bank, "Assets:Bank"),
+            AccountInfo::new(AccountKind::Income, "Income:Salary"),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(
+            tree.categories()
+                .map(|a| a.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["Expenses:Food:Bread"]
+        );
+        assert_eq!(
+            tree.sources().map(|a| a.name.as_str()).collect::<Vec<_>>(),
+            vec!["Assets:Bank"]
+        );
+    }
+
+    #[test]
+    fn test_children() {
+        let names = [
+            "Expenses:Food".to_string(),
+            "Expenses:Food:Bread".to_string(),
+            "Expenses:Food:Dairy:Milk".to_string(),
+            "Expenses:Transport".to_string(),
+        ];
+        assert_eq!(
+            children(&names, ""),
+            vec![Level::Group("Expenses".to_string())]
+        );
+        assert_eq!(
+            children(&names, "Expenses"),
+            vec![
+                Level::Group("Expenses:Food".to_string()),
+                Level::Leaf("Expenses:Food".to_string()),
+                Level::Leaf("Expenses:Transport".to_string()),
+            ]
+        );
+        assert_eq!(
+            children(&names, "Expenses:Food"),
+            vec![
+                Level::Group("Expenses:Food:Dairy".to_string()),
+                Level::Leaf("Expenses:Food:Bread".to_string()),
+            ]
+        );
+        assert!(children(&names, "Expenses:Fo").is_empty());
+    }
+}
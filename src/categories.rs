@@ -1,13 +1,16 @@
 #[cfg(feature = "telegram")]
 use crate::ui::input_category;
 use libc::isatty;
-use radix_trie::Trie;
+use radix_trie::{Trie, TrieCommon};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::cmp::Ordering;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
 
 /// Category statistics for single item
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CatStat {
     /// Category name
     category: String,
@@ -37,20 +40,51 @@ impl Eq for CatStat {}
 
 pub type CatStats = Trie<String, Vec<CatStat>>;
 
-/// Insert new `cat` into statistics vector or add single usage to existing cat
-fn update_stat(cat: &str, stat: &mut Vec<CatStat>) {
+impl CatStat {
+    pub fn category(&self) -> &str {
+        &self.category
+    }
+
+    pub fn hits(&self) -> i64 {
+        self.hits
+    }
+}
+
+/// Insert new `cat` into statistics vector or add `hits` usages to existing cat
+fn add_stat_hits(cat: &str, hits: i64, stat: &mut Vec<CatStat>) {
     let existing = stat.iter_mut().find(|stat| stat.category == cat);
     match existing {
-        Some(e) => e.hits += 1,
+        Some(e) => e.hits += hits,
         None => stat.push(CatStat {
             category: String::from(cat),
-            hits: 1,
+            hits,
         }),
     }
 
     stat.sort_by(|a, b| b.cmp(a));
 }
 
+/// Insert new `cat` into statistics vector or add single usage to existing cat
+fn update_stat(cat: &str, stat: &mut Vec<CatStat>) {
+    add_stat_hits(cat, 1, stat)
+}
+
+/// Add `hits` usages of `cat` for `item` in `storage`
+pub fn add_hits(item: &str, cat: &str, hits: i64, storage: &mut CatStats) {
+    match storage.get_mut(item) {
+        Some(stat) => add_stat_hits(cat, hits, stat),
+        None => {
+            storage.insert(
+                String::from(item),
+                vec![CatStat {
+                    category: String::from(cat),
+                    hits,
+                }],
+            );
+        }
+    }
+}
+
 /// Set up `cat` as category for `item`: update statistics or create new item
 /// in `storage`
 pub fn assign_category(item: &str, cat: &str, storage: &mut CatStats) {
@@ -70,16 +104,268 @@ pub fn assign_category(item: &str, cat: &str, storage: &mut CatStats) {
     }
 }
 
+/// Check if `category` is `name` itself or one of its subcategories
+fn is_subcategory(category: &str, name: &str) -> bool {
+    category == name
+        || category
+            .strip_prefix(name)
+            .is_some_and(|rest| rest.starts_with(':'))
+}
+
+/// Move all the hits of `from` (and its subcategories if `subcategories`)
+/// in `stat` to `to`, merging with the already existing entries. Returns
+/// `true` if anything was changed.
+fn rename_stat(from: &str, to: &str, stat: &mut Vec<CatStat>, subcategories: bool) -> bool {
+    let matches = |category: &str| {
+        if subcategories {
+            is_subcategory(category, from)
+        } else {
+            category == from
+        }
+    };
+    if !stat.iter().any(|s| matches(&s.category)) {
+        return false;
+    }
+
+    let mut renamed: Vec<CatStat> = Vec::with_capacity(stat.len());
+    for s in stat.drain(..) {
+        let category = if matches(&s.category) {
+            format!("{}{}", to, &s.category[from.len()..])
+        } else {
+            s.category
+        };
+        match renamed.iter_mut().find(|r| r.category == category) {
+            Some(r) => r.hits += s.hits,
+            None => renamed.push(CatStat {
+                category,
+                hits: s.hits,
+            }),
+        }
+    }
+
+    renamed.sort_by(|a, b| b.cmp(a));
+    *stat = renamed;
+    true
+}
+
+/// Rename category `from` to `to` for every item in `storage`. Subcategories
+/// are moved along, and if an item already has the `to` category the hits are
+/// merged. Returns the list of affected items.
+pub fn rename_category(from: &str, to: &str, storage: &mut CatStats) -> Vec<String> {
+    rename_items(from, to, storage, true)
+}
+
+/// Move the hits of exactly `from` to `to` for every item in `storage`,
+/// the subcategories stay. Stale parent accounts may have valid children.
+/// Returns the list of affected items.
+pub fn remap_category(from: &str, to: &str, storage: &mut CatStats) -> Vec<String> {
+    rename_items(from, to, storage, false)
+}
+
+fn rename_items(from: &str, to: &str, storage: &mut CatStats, subcategories: bool) -> Vec<String> {
+    if from.is_empty() || to.is_empty() || from == to {
+        return Vec::new();
+    }
+
+    let items: Vec<String> = storage.keys().cloned().collect();
+    let mut affected = Vec::new();
+    for item in items {
+        if let Some(stat) = storage.get_mut(&item) {
+            if rename_stat(from, to, stat, subcategories) {
+                affected.push(item);
+            }
+        }
+    }
+    affected
+}
+
+/// Learn categories from pairs of item names and categories. Categories
+/// missing in `accounts` are skipped unless no accounts are known. Returns
+/// the number of learned pairs.
+pub fn learn<F>(
+    pairs: &[(String, String)],
+    filter: F,
+    storage: &mut CatStats,
+    accounts: &HashSet<String>,
+) -> usize
+where
+    F: Fn(&str) -> Cow<'_, str>,
+{
+    let mut learned = 0;
+    for (item, cat) in pairs {
+        if cat.is_empty() || (!accounts.is_empty() && !accounts.contains(cat)) {
+            continue;
+        }
+        let key = filter(item);
+        if key.is_empty() {
+            continue;
+        }
+        assign_category(&key, cat, storage);
+        learned += 1;
+    }
+    learned
+}
+
+/// Key the statistics for `item` are looked up by. Items learned before the
+/// keys were normalized are stored under the numfilter and perekrestok
+/// filtered name by the command line tool and under the raw name by the bot,
+/// those are used when nothing is learned under the filtered `key`.
+pub fn learned_key<'a>(item: &'a str, key: Cow<'a, str>, storage: &CatStats) -> Cow<'a, str> {
+    if storage.get(key.as_ref()).is_some() {
+        return key;
+    }
+    let legacy = LineFilter::new().numfilter().perekrestok_filter().build()(item);
+    if storage.get(legacy.as_ref()).is_some() {
+        legacy
+    } else if storage.get(item).is_some() {
+        Cow::Borrowed(item)
+    } else {
+        key
+    }
+}
+
 /// Return most probable category for provided `item`
 pub fn get_top_category<'a>(item: &str, storage: &'a CatStats) -> Option<&'a str> {
     storage.get(item).map(|s| -> &'a str { &s[0].category })
 }
 
-/// Choose proper category or ask user
-pub fn get_category(item: &str, storage: &mut CatStats, accounts: &HashSet<String>) -> String {
+/// Return most probable category for `item` which still exists in
+/// `accounts`. Stale categories are skipped. If no accounts are known, every
+/// category is considered valid.
+pub fn get_valid_category<'a>(
+    item: &str,
+    storage: &'a CatStats,
+    accounts: &HashSet<String>,
+) -> Option<&'a str> {
+    if accounts.is_empty() {
+        return get_top_category(item, storage);
+    }
+
+    storage.get(item).and_then(|stats| {
+        stats
+            .iter()
+            .find(|s| accounts.contains(&s.category))
+            .map(|s| -> &'a str { &s.category })
+    })
+}
+
+/// Collect categories from `storage` which are missing in `accounts`,
+/// along with the items that refer to them
+pub fn stale_categories(
+    storage: &CatStats,
+    accounts: &HashSet<String>,
+) -> BTreeMap<String, Vec<String>> {
+    let mut result: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    if accounts.is_empty() {
+        return result;
+    }
+
+    for (item, stats) in storage.iter() {
+        for stat in stats.iter().filter(|s| !accounts.contains(&s.category)) {
+            result
+                .entry(stat.category.clone())
+                .or_default()
+                .push(item.clone());
+        }
+    }
+    result
+}
+
+/// Details on how the category for an item was chosen
+#[derive(Debug)]
+pub struct Explanation {
+    /// Item name as in receipt
+    pub item: String,
+    /// Item name after `LineFilter`
+    pub filtered: String,
+    /// Key found in statistics
+    pub key: Option<String>,
+    /// All the known categories for the key, most probable first
+    pub stats: Vec<CatStat>,
+    /// Category which would be chosen
+    pub category: Option<String>,
+    /// Human readable decision reason
+    pub reason: String,
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Item: {}", self.item)?;
+        writeln!(f, "Filtered: {}", self.filtered)?;
+        match &self.key {
+            Some(key) => writeln!(f, "Matched key: {}", key)?,
+            None => writeln!(f, "Matched key: none")?,
+        }
+        for stat in &self.stats {
+            writeln!(f, "  {}: {} hits", stat.category, stat.hits)?;
+        }
+        match &self.category {
+            Some(cat) => writeln!(f, "Category: {}", cat)?,
+            None => writeln!(f, "Category: none")?,
+        }
+        write!(f, "Reason: {}", self.reason)
+    }
+}
+
+/// Explain which category would be chosen for `item` and why
+pub fn explain<F>(
+    item: &str,
+    filter: F,
+    storage: &CatStats,
+    accounts: &HashSet<String>,
+) -> Explanation
+where
+    F: Fn(&str) -> Cow<'_, str>,
+{
+    let filtered = learned_key(item, filter(item), storage).into_owned();
+    let stats: Vec<CatStat> = storage.get(&filtered).cloned().unwrap_or_default();
+    let key = if stats.is_empty() {
+        None
+    } else {
+        Some(filtered.clone())
+    };
+    let category = get_valid_category(&filtered, storage, accounts).map(String::from);
+
+    let total: i64 = stats.iter().map(|s| s.hits).sum();
+    let reason = match (&category, stats.first()) {
+        (None, None) => "No statistics for the item".to_string(),
+        (None, Some(_)) => "All the learned categories are missing in accounts".to_string(),
+        (Some(cat), Some(top)) if *cat == top.category => format!(
+            "Top category with {} of {} hits (score {:.2})",
+            top.hits,
+            total,
+            top.hits as f64 / total as f64
+        ),
+        (Some(cat), _) => {
+            let hits = stats
+                .iter()
+                .find(|s| s.category == *cat)
+                .map_or(0, |s| s.hits);
+            format!(
+                "Top category is missing in accounts, best valid one has {} of {} hits (score {:.2})",
+                hits,
+                total,
+                hits as f64 / total as f64
+            )
+        }
+    };
+
+    Explanation {
+        item: item.to_string(),
+        filtered,
+        key,
+        stats,
+        category,
+        reason,
+    }
+}
+
+/// Choose proper category or ask user. Returns the category and whether it
+/// was entered by user, so it's to be learned.
+pub fn get_category(item: &str, storage: &CatStats, accounts: &HashSet<String>) -> (String, bool) {
     let istty = unsafe { isatty(libc::STDOUT_FILENO) } != 0;
     if istty {
-        let topcat = match get_top_category(item, storage) {
+        let topcat = match get_valid_category(item, storage, accounts) {
             Some(cat) => String::from(cat),
             None => String::new(),
         };
@@ -89,100 +375,325 @@ pub fn get_category(item: &str, storage: &mut CatStats, accounts: &HashSet<Strin
             .collect();
         let cat = input_category(item, &topcat, &cats);
         if cat.is_empty() {
-            topcat
+            (topcat, false)
         } else {
-            assign_category(item, &cat, storage);
-            cat
+            (cat, true)
         }
     } else {
-        match get_top_category(item, storage) {
-            Some(cat) => String::from(cat),
-            None => String::new(),
+        (get_category_noninteractive(item, storage, accounts), false)
+    }
+}
+
+/// Choose proper category without asking user, empty if none is known
+pub fn get_category_noninteractive(
+    item: &str,
+    storage: &CatStats,
+    accounts: &HashSet<String>,
+) -> String {
+    match get_valid_category(item, storage, accounts) {
+        Some(cat) => String::from(cat),
+        None => String::new(),
+    }
+}
+
+/// Units trimmed from the end of item names by default
+pub const DEFAULT_UNITS: [&str; 5] = ["кг", "г", "мл", "л", "шт"];
+
+/// Apply a slicing function to `input` keeping it borrowed when possible
+fn map_slice<'a, M>(input: Cow<'a, str>, m: M) -> Cow<'a, str>
+where
+    M: Fn(&str) -> &str,
+{
+    match input {
+        Cow::Borrowed(s) => Cow::Borrowed(m(s)),
+        Cow::Owned(s) => Cow::Owned(m(&s).to_string()),
+    }
+}
+
+/// Trim the `units` with numbers in front of them from the end of `input`
+fn trim_units<'a>(input: &'a str, units: &[String]) -> &'a str {
+    let mut trimmed = input;
+
+    loop {
+        let original = trimmed;
+        for unit in units {
+            if trimmed.ends_with(unit.as_str()) {
+                trimmed = trimmed
+                    .trim_end_matches(unit.as_str())
+                    .trim_end_matches(',')
+                    .trim_end();
+            }
+        }
+
+        // Trim any numeric characters and commas at the end.
+        trimmed = trimmed
+            .trim_end_matches(char::is_numeric)
+            .trim_end_matches(',')
+            .trim_end();
+
+        // If no changes were made in this iteration, break the loop.
+        if trimmed == original {
+            break;
+        }
+    }
+
+    trimmed
+}
+
+/// Latin letters which look exactly like Cyrillic ones
+const HOMOGLYPHS: [(char, char); 19] = [
+    ('A', 'А'),
+    ('B', 'В'),
+    ('C', 'С'),
+    ('E', 'Е'),
+    ('H', 'Н'),
+    ('K', 'К'),
+    ('M', 'М'),
+    ('O', 'О'),
+    ('P', 'Р'),
+    ('T', 'Т'),
+    ('X', 'Х'),
+    ('Y', 'У'),
+    ('a', 'а'),
+    ('c', 'с'),
+    ('e', 'е'),
+    ('o', 'о'),
+    ('p', 'р'),
+    ('x', 'х'),
+    ('y', 'у'),
+];
+
+fn is_cyrillic(c: char) -> bool {
+    matches!(c, '\u{0400}'..='\u{04FF}')
+}
+
+/// Replace Latin lookalikes with Cyrillic letters in the words which already
+/// contain Cyrillic, so "Теpминал" with Latin `p` becomes "Терминал"
+fn repair_homoglyphs(input: &str) -> Cow<'_, str> {
+    let mixed = input
+        .split_whitespace()
+        .any(|w| w.chars().any(is_cyrillic) && w.chars().any(|c| c.is_ascii_alphabetic()));
+    if !mixed {
+        return Cow::Borrowed(input);
+    }
+
+    let mut result = String::with_capacity(input.len());
+    let mut word = String::new();
+    let flush = |word: &mut String, result: &mut String| {
+        if word.chars().any(is_cyrillic) {
+            result.extend(word.chars().map(|c| {
+                HOMOGLYPHS
+                    .iter()
+                    .find(|(latin, _)| *latin == c)
+                    .map_or(c, |(_, cyr)| *cyr)
+            }));
+        } else {
+            result.push_str(word);
         }
+        word.clear();
+    };
+    for c in input.chars() {
+        if c.is_whitespace() {
+            flush(&mut word, &mut result);
+            result.push(c);
+        } else {
+            word.push(c);
+        }
+    }
+    flush(&mut word, &mut result);
+    Cow::Owned(result)
+}
+
+/// Drop quotes and replace punctuation with spaces. Decimal separators
+/// between digits are kept, so "0.5" stays as is.
+fn strip_punctuation(input: &str) -> Cow<'_, str> {
+    if !input
+        .chars()
+        .any(|c| c.is_ascii_punctuation() || "«»„“”‘’".contains(c))
+    {
+        return Cow::Borrowed(input);
+    }
+
+    let chars: Vec<char> = input.chars().collect();
+    let mut result = String::with_capacity(input.len());
+    for (i, &c) in chars.iter().enumerate() {
+        if "\"'«»„“”‘’`".contains(c) {
+            continue;
+        }
+        let decimal = (c == '.' || c == ',')
+            && i > 0
+            && chars[i - 1].is_ascii_digit()
+            && chars.get(i + 1).is_some_and(|n| n.is_ascii_digit());
+        if c.is_ascii_punctuation() && !decimal {
+            result.push(' ');
+        } else {
+            result.push(c);
+        }
+    }
+    Cow::Owned(result)
+}
+
+/// Replace runs of whitespace with a single space and trim the line
+fn collapse_whitespace(input: &str) -> Cow<'_, str> {
+    let trimmed = input.trim();
+    if trimmed.contains("  ") || trimmed.contains(|c: char| c.is_whitespace() && c != ' ') {
+        Cow::Owned(trimmed.split_whitespace().collect::<Vec<&str>>().join(" "))
+    } else {
+        Cow::Borrowed(trimmed)
+    }
+}
+
+/// Apply an owning transformation to `input` produced by a previous step
+fn map_owned<'a, M>(input: Cow<'a, str>, m: M) -> Cow<'a, str>
+where
+    M: Fn(&str) -> Cow<'_, str>,
+{
+    match input {
+        Cow::Borrowed(s) => m(s),
+        Cow::Owned(s) => Cow::Owned(m(&s).into_owned()),
     }
 }
 
+/// Type-erased filter to build pipelines at runtime
+pub type BoxedFilter = Box<dyn Fn(&str) -> Cow<'_, str> + Send + Sync>;
+
 pub struct LineFilter<F>
 where
-    F: Fn(&str) -> &str,
+    F: Fn(&str) -> Cow<'_, str>,
 {
     filter: F,
 }
 
-impl LineFilter<fn(&str) -> &str> {
+impl LineFilter<fn(&str) -> Cow<'_, str>> {
     pub fn new() -> Self {
         Self {
-            filter: |input| input,
+            filter: |input| Cow::Borrowed(input),
         }
     }
 }
 
 impl<F> LineFilter<F>
 where
-    F: Fn(&str) -> &str,
+    F: Fn(&str) -> Cow<'_, str>,
 {
-    pub fn numfilter(self) -> LineFilter<impl Fn(&str) -> &str> {
+    pub fn numfilter(self) -> LineFilter<impl Fn(&str) -> Cow<'_, str>> {
         LineFilter {
             filter: move |input| {
-                let intermediate = (self.filter)(input);
-                intermediate
-                    .trim_start()
-                    .trim_start_matches(char::is_numeric)
-                    .trim_start()
+                map_slice((self.filter)(input), |intermediate| {
+                    intermediate
+                        .trim_start()
+                        .trim_start_matches(char::is_numeric)
+                        .trim_start()
+                })
             },
         }
     }
 
-    pub fn perekrestok_filter(self) -> LineFilter<impl Fn(&str) -> &str> {
+    pub fn perekrestok_filter(self) -> LineFilter<impl Fn(&str) -> Cow<'_, str>> {
         LineFilter {
             filter: move |input| {
-                let intermediate = (self.filter)(input);
-                intermediate
-                    .trim_start()
-                    .trim_start_matches(char::is_numeric)
-                    .trim_start_matches(['*', ':', ' '])
-                    .trim_start()
+                map_slice((self.filter)(input), |intermediate| {
+                    intermediate
+                        .trim_start()
+                        .trim_start_matches(char::is_numeric)
+                        .trim_start_matches(['*', ':', ' '])
+                        .trim_start()
+                })
             },
         }
     }
 
-    pub fn trim_units_from_end(self) -> LineFilter<impl Fn(&str) -> &str> {
+    pub fn trim_units_from_end(self) -> LineFilter<impl Fn(&str) -> Cow<'_, str>> {
+        self.trim_units(DEFAULT_UNITS.iter().map(|u| u.to_string()).collect())
+    }
+
+    /// Trim any of `units` along with amounts from the end of line
+    pub fn trim_units(self, units: Vec<String>) -> LineFilter<impl Fn(&str) -> Cow<'_, str>> {
         LineFilter {
             filter: move |input| {
-                let intermediate = (self.filter)(input);
-                let units = ["кг", "г", "мл", "л", "шт"];
-
-                let mut trimmed = intermediate;
-
-                loop {
-                    let original = trimmed;
-                    for unit in &units {
-                        if trimmed.ends_with(unit) {
-                            trimmed = trimmed
-                                .trim_end_matches(unit)
-                                .trim_end_matches(',')
-                                .trim_end();
-                        }
-                    }
+                map_slice((self.filter)(input), |intermediate| {
+                    trim_units(intermediate, &units)
+                })
+            },
+        }
+    }
 
-                    // Trim any numeric characters and commas at the end.
-                    trimmed = trimmed
-                        .trim_end_matches(char::is_numeric)
-                        .trim_end_matches(',')
-                        .trim_end();
+    /// Replace every match of `re` with `with`
+    pub fn replace(self, re: Regex, with: String) -> LineFilter<impl Fn(&str) -> Cow<'_, str>> {
+        LineFilter {
+            filter: move |input| {
+                map_owned((self.filter)(input), |s| re.replace_all(s, with.as_str()))
+            },
+        }
+    }
 
-                    // If no changes were made in this iteration, break the loop.
-                    if trimmed == original {
-                        break;
+    /// Convert the line to lower case
+    pub fn casefold(self) -> LineFilter<impl Fn(&str) -> Cow<'_, str>> {
+        LineFilter {
+            filter: move |input| {
+                map_owned((self.filter)(input), |s| {
+                    if s.chars().any(char::is_uppercase) {
+                        Cow::Owned(s.to_lowercase())
+                    } else {
+                        Cow::Borrowed(s)
                     }
-                }
+                })
+            },
+        }
+    }
+
+    /// Replace Latin letters looking like Cyrillic ones in Cyrillic words
+    pub fn homoglyphs(self) -> LineFilter<impl Fn(&str) -> Cow<'_, str>> {
+        LineFilter {
+            filter: move |input| map_owned((self.filter)(input), repair_homoglyphs),
+        }
+    }
+
+    /// Collapse runs of whitespace into single spaces
+    pub fn collapse_whitespace(self) -> LineFilter<impl Fn(&str) -> Cow<'_, str>> {
+        LineFilter {
+            filter: move |input| map_owned((self.filter)(input), collapse_whitespace),
+        }
+    }
 
-                trimmed
+    /// Strip quotes and punctuation
+    pub fn strip_punctuation(self) -> LineFilter<impl Fn(&str) -> Cow<'_, str>> {
+        LineFilter {
+            filter: move |input| map_owned((self.filter)(input), strip_punctuation),
+        }
+    }
+
+    /// Keep only the normalized product part of the name, without brand and
+    /// sizes
+    pub fn product(self) -> LineFilter<impl Fn(&str) -> Cow<'_, str>> {
+        LineFilter {
+            filter: move |input| {
+                map_owned((self.filter)(input), |s| {
+                    Cow::Owned(crate::product::parse(s).key())
+                })
             },
         }
     }
 
-    pub fn build(self) -> impl Fn(&str) -> &str {
+    /// All the normalization steps, so lookalike names map to the same key
+    pub fn normalize(self) -> LineFilter<impl Fn(&str) -> Cow<'_, str>> {
+        self.homoglyphs()
+            .strip_punctuation()
+            .collapse_whitespace()
+            .casefold()
+    }
+
+    /// Erase the filter type, so the steps can be chained at runtime
+    pub fn boxed(self) -> LineFilter<BoxedFilter>
+    where
+        F: Send + Sync + 'static,
+    {
+        LineFilter {
+            filter: Box::new(self.filter),
+        }
+    }
+
+    pub fn build(self) -> impl Fn(&str) -> Cow<'_, str> {
         self.filter
     }
 }
@@ -221,6 +732,175 @@ mod tests {
         assert_eq!(topcat, "category");
     }
 
+    #[test]
+    fn test_rename_category() {
+        let mut cm: CatStats = Trie::new();
+        assign_category("chips", "Expenses:Food:Snacks", &mut cm);
+        assign_category("nuts", "Expenses:Food:Snacks:Nuts", &mut cm);
+        assign_category("milk", "Expenses:Food:Dairy", &mut cm);
+
+        let affected =
+            rename_category("Expenses:Food:Snacks", "Expenses:Groceries:Snacks", &mut cm);
+        assert_eq!(affected, vec!["chips", "nuts"]);
+        assert_eq!(
+            get_top_category("chips", &cm).unwrap(),
+            "Expenses:Groceries:Snacks"
+        );
+        assert_eq!(
+            get_top_category("nuts", &cm).unwrap(),
+            "Expenses:Groceries:Snacks:Nuts"
+        );
+        assert_eq!(
+            get_top_category("milk", &cm).unwrap(),
+            "Expenses:Food:Dairy"
+        );
+    }
+
+    #[test]
+    fn test_merge_category() {
+        let mut cm: CatStats = Trie::new();
+        assign_category("cola", "Expenses:Drinks", &mut cm);
+        assign_category("cola", "Expenses:Soda", &mut cm);
+        assign_category("cola", "Expenses:Soda", &mut cm);
+        assign_category("cola", "Expenses:Food", &mut cm);
+
+        let affected = rename_category("Expenses:Soda", "Expenses:Drinks", &mut cm);
+        assert_eq!(affected, vec!["cola"]);
+        let stats = cm.get("cola").unwrap();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].category, "Expenses:Drinks");
+        assert_eq!(stats[0].hits, 3);
+
+        assert!(rename_category("Expenses:Sod", "Expenses:Other", &mut cm).is_empty());
+    }
+
+    #[test]
+    fn test_stale_categories() {
+        let mut cm: CatStats = Trie::new();
+        assign_category("chips", "Expenses:Food:Snacks", &mut cm);
+        assign_category("chips", "Expenses:Snacks", &mut cm);
+        assign_category("chips", "Expenses:Snacks", &mut cm);
+        assign_category("milk", "Expenses:Food:Dairy", &mut cm);
+
+        let accounts: HashSet<String> = HashSet::from([
+            "Expenses:Food:Snacks".to_string(),
+            "Expenses:Food:Dairy".to_string(),
+        ]);
+
+        let stale = stale_categories(&cm, &accounts);
+        assert_eq!(stale.len(), 1);
+        assert_eq!(stale["Expenses:Snacks"], vec!["chips"]);
+
+        assert_eq!(get_top_category("chips", &cm).unwrap(), "Expenses:Snacks");
+        assert_eq!(
+            get_valid_category("chips", &cm, &accounts).unwrap(),
+            "Expenses:Food:Snacks"
+        );
+        assert_eq!(
+            get_valid_category("chips", &cm, &HashSet::new()).unwrap(),
+            "Expenses:Snacks"
+        );
+        assert!(stale_categories(&cm, &HashSet::new()).is_empty());
+    }
+
+    #[test]
+    fn test_remap_stale_parent() {
+        let mut cm: CatStats = Trie::new();
+        assign_category("snacks", "Expenses:Food", &mut cm);
+        assign_category("chips", "Expenses:Food:Snacks", &mut cm);
+        assign_category("chips", "Expenses:Food", &mut cm);
+
+        // Placeholder parent is stale while its children are valid
+        let accounts: HashSet<String> = HashSet::from([
+            "Expenses:Food:Snacks".to_string(),
+            "Expenses:Groceries".to_string(),
+        ]);
+        let stale = stale_categories(&cm, &accounts);
+        assert_eq!(stale.keys().collect::<Vec<_>>(), vec!["Expenses:Food"]);
+
+        let affected = remap_category("Expenses:Food", "Expenses:Groceries", &mut cm);
+        assert_eq!(affected, vec!["chips", "snacks"]);
+        assert_eq!(
+            get_top_category("snacks", &cm).unwrap(),
+            "Expenses:Groceries"
+        );
+        let chips: Vec<&str> = cm
+            .get("chips")
+            .unwrap()
+            .iter()
+            .map(|s| s.category.as_str())
+            .collect();
+        assert!(chips.contains(&"Expenses:Food:Snacks"));
+        assert!(chips.contains(&"Expenses:Groceries"));
+        assert!(stale_categories(&cm, &accounts).is_empty());
+    }
+
+    #[test]
+    fn test_learned_key() {
+        let mut cm: CatStats = Trie::new();
+        assign_category("1: *Milk", "Expenses:Dairy", &mut cm);
+        let filter = LineFilter::new().perekrestok_filter().normalize().build();
+        assert_eq!(learned_key("1: *Milk", filter("1: *Milk"), &cm), "1: *Milk");
+
+        assign_category("milk", "Expenses:Dairy", &mut cm);
+        assert_eq!(learned_key("1: *Milk", filter("1: *Milk"), &cm), "milk");
+        assert_eq!(learned_key("Bread", filter("Bread"), &cm), "bread");
+
+        // The command line tool stored numfilter and perekrestok output
+        assign_category("Сидр 0.5", "Expenses:Drinks", &mut cm);
+        assign_category("2: *Сидр 0.5", "Expenses:Food", &mut cm);
+        assert_eq!(
+            learned_key("2: *Сидр 0.5", filter("2: *Сидр 0.5"), &cm),
+            "Сидр 0.5"
+        );
+    }
+
+    #[test]
+    fn test_explain() {
+        let mut cm: CatStats = Trie::new();
+        assign_category("Milk", "Expenses:Food:Dairy", &mut cm);
+        assign_category("Milk", "Expenses:Food:Dairy", &mut cm);
+        assign_category("Milk", "Expenses:Food", &mut cm);
+        let filter = LineFilter::new().numfilter().build();
+
+        let exp = explain("12 Milk", &filter, &cm, &HashSet::new());
+        assert_eq!(exp.filtered, "Milk");
+        assert_eq!(exp.key.as_deref(), Some("Milk"));
+        assert_eq!(exp.stats.len(), 2);
+        assert_eq!(exp.category.as_deref(), Some("Expenses:Food:Dairy"));
+        assert!(exp.reason.contains("2 of 3"));
+
+        let accounts = HashSet::from(["Expenses:Food".to_string()]);
+        let exp = explain("Milk", &filter, &cm, &accounts);
+        assert_eq!(exp.category.as_deref(), Some("Expenses:Food"));
+        assert!(exp.reason.contains("missing"));
+
+        let exp = explain("Bread", &filter, &cm, &accounts);
+        assert!(exp.key.is_none());
+        assert!(exp.category.is_none());
+        assert!(exp.to_string().contains("Matched key: none"));
+    }
+
+    #[test]
+    fn test_learn() {
+        let mut cm: CatStats = Trie::new();
+        let pairs = vec![
+            ("12 Milk".to_string(), "Expenses:Dairy".to_string()),
+            ("Milk".to_string(), "Expenses:Dairy".to_string()),
+            ("Bread".to_string(), "Assets:Wallet".to_string()),
+            ("12".to_string(), "Expenses:Dairy".to_string()),
+        ];
+        let accounts = HashSet::from(["Expenses:Dairy".to_string()]);
+        let filter = LineFilter::new().numfilter().build();
+
+        assert_eq!(learn(&pairs, &filter, &mut cm, &accounts), 2);
+        assert_eq!(cm.get("Milk").unwrap()[0].hits, 2);
+        assert!(cm.get("Bread").is_none());
+
+        assert_eq!(learn(&pairs, &filter, &mut cm, &HashSet::new()), 3);
+        assert_eq!(get_top_category("Bread", &cm).unwrap(), "Assets:Wallet");
+    }
+
     #[test]
     fn test_new() {
         let filter = LineFilter::new();
@@ -245,6 +925,84 @@ mod tests {
         assert_eq!(filter.build()("123: *Hello"), "Hello");
     }
 
+    #[test]
+    fn test_trim_custom_units() {
+        let filter = LineFilter::new()
+            .trim_units(vec!["pcs".to_string(), "oz".to_string()])
+            .build();
+        assert_eq!(filter("Eggs 10pcs"), "Eggs");
+        assert_eq!(filter("Coffee 12 oz"), "Coffee");
+        assert_eq!(filter("Water 150мл"), "Water 150мл");
+    }
+
+    #[test]
+    fn test_replace() {
+        let filter = LineFilter::new()
+            .numfilter()
+            .replace(Regex::new(r"\s*ВЕС\b").unwrap(), String::new())
+            .build();
+        assert_eq!(filter("12 ЯБЛОКИ ВЕС"), "ЯБЛОКИ");
+    }
+
+    #[test]
+    fn test_casefold() {
+        let filter = LineFilter::new().casefold().build();
+        assert_eq!(filter("ХЛЕБ Украинский"), "хлеб украинский");
+        assert!(matches!(filter("milk"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn test_homoglyphs() {
+        let filter = LineFilter::new().homoglyphs().build();
+        assert_eq!(filter("Теpминал 24"), "Терминал 24");
+        assert_eq!(filter("СИДР 0.5 MAGNERS APP"), "СИДР 0.5 MAGNERS APP");
+        assert_eq!(filter("MOЛOKO Parmalat"), "МОЛОКО Parmalat");
+    }
+
+    #[test]
+    fn test_collapse_whitespace() {
+        let filter = LineFilter::new().collapse_whitespace().build();
+        assert_eq!(filter("  Honey   100\t г "), "Honey 100 г");
+        assert!(matches!(filter("Honey 100 г"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn test_strip_punctuation() {
+        let filter = LineFilter::new().strip_punctuation().build();
+        assert_eq!(
+            filter(r#"Сыр "Фитнес" безлактозный, 200г"#),
+            "Сыр Фитнес безлактозный  200г"
+        );
+        assert_eq!(filter("СОУС ОСТР.380Г «КИНТО»"), "СОУС ОСТР 380Г КИНТО");
+        assert_eq!(filter("СИДР 0.5 MAGNERS"), "СИДР 0.5 MAGNERS");
+    }
+
+    #[test]
+    fn test_normalize() {
+        let filter = LineFilter::new().normalize().build();
+        assert_eq!(
+            filter(r#"Сыр  "Фитнес"  БЕЗЛАКТОЗНЫЙ,200г"#),
+            filter("сыр фитнес безлактозный 200г")
+        );
+        assert_eq!(filter("Теpминал"), filter("ТЕРМИНАЛ"));
+    }
+
+    #[test]
+    fn test_product() {
+        let filter = LineFilter::new().product().build();
+        assert_eq!(filter("СОУС ОСТР.380Г КИНТО"), "соус остр");
+    }
+
+    #[test]
+    fn test_boxed() {
+        let mut filter = LineFilter::new().boxed();
+        for _ in 0..2 {
+            filter = filter.numfilter().boxed();
+        }
+        filter = filter.trim_units_from_end().boxed();
+        assert_eq!(filter.build()("12 Milk 2 л"), "Milk");
+    }
+
     #[test]
     fn test_trim_no_unit() {
         let filter = LineFilter::new().trim_units_from_end().build();
@@ -1,11 +1,11 @@
 #[cfg(feature = "telegram")]
-use crate::categories::get_top_category;
-use crate::categories::CatStats;
+use crate::categories::{get_valid_category, learned_key};
 use crate::receipt;
 use crate::user::User;
 use chrono::{DateTime, Utc};
 use qif_generator::{account::Account, split::Split, transaction::Transaction};
-use std::collections::{HashMap, HashSet};
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::fs;
 
 /// Read json file with receipt and convert it into `receipt::Purchase`
@@ -17,21 +17,29 @@ pub fn read_file(f: &str) -> receipt::Purchase {
 /// Generate set of QIF Splits from a Purchase items
 pub fn gen_splits<F, C>(
     items: &[receipt::Item],
-    cs: &mut CatStats,
-    accounts: &HashSet<String>,
+    user: &mut User,
     filter: F,
     categorizer: C,
 ) -> Vec<Split>
 where
-    C: Fn(&str, &mut CatStats, &HashSet<String>) -> String,
-    F: Fn(&str) -> &str,
+    C: Fn(&str, &mut User) -> String,
+    F: Fn(&str) -> Cow<'_, str>,
 {
     let mut result: Vec<Split> = Vec::new();
     for i in items.iter() {
+        let category = categorizer(i.name.as_str(), user);
+        if !user.accounts.is_empty() && !category.is_empty() && !user.accounts.contains(&category) {
+            log::warn!(
+                "Category {} for item {} is not in the account list",
+                category,
+                i.name
+            );
+        }
+
         let t = Split::new()
-            .memo(filter(i.name.as_str()))
+            .memo(&filter(i.name.as_str()))
             .amount(-i.sum)
-            .category(&categorizer(i.name.as_str(), cs, accounts))
+            .category(&category)
             .build();
 
         result.push(t);
@@ -72,14 +80,23 @@ pub fn gen_trans<'a>(
     }
 }
 
-/// Build a fully automatically categorized list
+/// Build a fully automatically categorized list. Items are looked up by the
+/// key produced with `key_filter`.
 #[cfg(feature = "telegram")]
-pub fn auto_cat_items(filename: &str, user: &User) -> (HashMap<String, String>, Vec<String>) {
+pub fn auto_cat_items<F>(
+    filename: &str,
+    user: &User,
+    key_filter: F,
+) -> (HashMap<String, String>, Vec<String>)
+where
+    F: Fn(&str) -> Cow<'_, str>,
+{
     let file = read_file(filename);
     let mut categorized: HashMap<String, String> = HashMap::new();
     let mut uncategorized: Vec<String> = Vec::new();
     for i in file.items {
-        if let Some(category) = get_top_category(i.name.as_str(), &user.catmap) {
+        let key = learned_key(&i.name, key_filter(&i.name), &user.catmap);
+        if let Some(category) = get_valid_category(&key, &user.catmap, &user.accounts) {
             categorized.insert(i.name, category.to_string());
         } else {
             uncategorized.push(i.name)
@@ -88,9 +105,9 @@ pub fn auto_cat_items(filename: &str, user: &User) -> (HashMap<String, String>,
     (categorized, uncategorized)
 }
 
-/// Convert `filename` into a QIF transaction
+/// Convert `purchase` into a QIF transaction
 pub fn convert<'a, F, C>(
-    filename: &'a str,
+    purchase: &receipt::Purchase,
     memo: &str,
     user: &'a mut User,
     acc: &'a Account,
@@ -98,17 +115,10 @@ pub fn convert<'a, F, C>(
     categorizer: C,
 ) -> Result<Transaction<'a>, String>
 where
-    F: Fn(&str) -> &str,
-    C: Fn(&str, &mut CatStats, &HashSet<String>) -> String,
+    F: Fn(&str) -> Cow<'_, str>,
+    C: Fn(&str, &mut User) -> String,
 {
-    let purchase = &read_file(filename);
-    let splits = &gen_splits(
-        &purchase.items,
-        &mut user.catmap,
-        &user.accounts,
-        &filter,
-        &categorizer,
-    );
+    let splits = &gen_splits(&purchase.items, user, &filter, &categorizer);
     gen_trans(acc, purchase.date(), purchase.total_sum(), memo, splits)
 }
 
@@ -128,6 +138,17 @@ mod tests {
         assert_eq!(result[0].sum, 17713);
     }
 
+    #[test]
+    fn test_read_store() {
+        let mut p = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        p.push("tests/resources/test.json");
+        let full_path = p.to_string_lossy();
+
+        let result = read_file(&full_path);
+        assert_eq!(result.store(), Some("АШАН - Авиапарк"));
+        assert_eq!(result.id(), "9282440300829284:28230:1706439950");
+    }
+
     #[test]
     fn test_read_file() {
         let mut p = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
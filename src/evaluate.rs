@@ -0,0 +1,219 @@
+use crate::categories::{get_category_noninteractive, CatStats};
+use csv::ReaderBuilder;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+
+/// Item name labelled with the expected category
+#[derive(Debug, Clone)]
+pub struct Sample {
+    pub item: String,
+    pub category: String,
+}
+
+/// Read labelled dataset from csv file with `item,category` columns
+pub fn read_dataset(path: &Path) -> Result<Vec<Sample>, Box<dyn Error>> {
+    let mut rdr = ReaderBuilder::new().has_headers(true).from_path(path)?;
+    let mut result = Vec::<Sample>::new();
+    for e in rdr.records() {
+        let record = e?;
+        let (Some(item), Some(category)) = (record.get(0), record.get(1)) else {
+            return Err(format!(
+                "No category in the dataset line {}",
+                record.position().map_or(0, |p| p.line())
+            )
+            .into());
+        };
+        result.push(Sample {
+            item: item.to_string(),
+            category: category.to_string(),
+        });
+    }
+    Ok(result)
+}
+
+/// Categorization quality over a labelled dataset
+#[derive(Debug, Default)]
+pub struct Evaluation {
+    /// Number of samples
+    pub total: usize,
+    /// Samples which got any category
+    pub covered: usize,
+    /// Samples which got the expected category
+    pub correct: usize,
+    /// Items without category
+    pub missed: Vec<String>,
+    /// Items per (expected, got) pair of wrongly chosen categories
+    pub confusion: BTreeMap<(String, String), Vec<String>>,
+}
+
+impl Evaluation {
+    /// Share of the right categories among the chosen ones
+    pub fn precision(&self) -> f64 {
+        if self.covered == 0 {
+            0.0
+        } else {
+            self.correct as f64 / self.covered as f64
+        }
+    }
+
+    /// Share of the samples which got any category
+    pub fn coverage(&self) -> f64 {
+        if self.total == 0 {
+            0.0
+        } else {
+            self.covered as f64 / self.total as f64
+        }
+    }
+}
+
+impl fmt::Display for Evaluation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Samples: {}", self.total)?;
+        writeln!(
+            f,
+            "Precision: {:.3} ({} of {})",
+            self.precision(),
+            self.correct,
+            self.covered
+        )?;
+        writeln!(
+            f,
+            "Coverage: {:.3} ({} of {})",
+            self.coverage(),
+            self.covered,
+            self.total
+        )?;
+        if !self.confusion.is_empty() {
+            writeln!(f, "Confusion:")?;
+            for ((expected, got), items) in &self.confusion {
+                writeln!(f, "  {} -> {}: {}", expected, got, items.join(", "))?;
+            }
+        }
+        if !self.missed.is_empty() {
+            writeln!(f, "Missed:")?;
+            for item in &self.missed {
+                writeln!(f, "  {}", item)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Replay `samples` through `filter` and non-interactive categorization
+pub fn evaluate<F>(
+    samples: &[Sample],
+    filter: F,
+    storage: &CatStats,
+    accounts: &HashSet<String>,
+) -> Evaluation
+where
+    F: Fn(&str) -> Cow<'_, str>,
+{
+    let mut result = Evaluation {
+        total: samples.len(),
+        ..Default::default()
+    };
+
+    for sample in samples {
+        let got = get_category_noninteractive(&filter(&sample.item), storage, accounts);
+        if got.is_empty() {
+            result.missed.push(sample.item.clone());
+            continue;
+        }
+
+        result.covered += 1;
+        if got == sample.category {
+            result.correct += 1;
+        } else {
+            result
+                .confusion
+                .entry((sample.category.clone(), got))
+                .or_default()
+                .push(sample.item.clone());
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::categories::assign_category;
+    use crate::filters::FilterConfig;
+    use radix_trie::Trie;
+    use std::path::PathBuf;
+
+    fn resource(name: &str) -> PathBuf {
+        let mut p = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        p.push("tests/resources");
+        p.push(name);
+        p
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let mut cm: CatStats = Trie::new();
+        assign_category("milk", "Expenses:Dairy", &mut cm);
+        assign_category("bread", "Expenses:Food", &mut cm);
+        let samples = vec![
+            Sample {
+                item: "milk".to_string(),
+                category: "Expenses:Dairy".to_string(),
+            },
+            Sample {
+                item: "bread".to_string(),
+                category: "Expenses:Bread".to_string(),
+            },
+            Sample {
+                item: "eggs".to_string(),
+                category: "Expenses:Food".to_string(),
+            },
+        ];
+
+        let filter = crate::categories::LineFilter::new().build();
+        let eval = evaluate(&samples, filter, &cm, &HashSet::new());
+        assert_eq!(eval.total, 3);
+        assert_eq!(eval.covered, 2);
+        assert_eq!(eval.correct, 1);
+        assert_eq!(eval.precision(), 0.5);
+        assert_eq!(eval.missed, vec!["eggs"]);
+        assert_eq!(
+            eval.confusion[&("Expenses:Bread".to_string(), "Expenses:Food".to_string())],
+            vec!["bread"]
+        );
+        assert!(eval
+            .to_string()
+            .contains("Expenses:Bread -> Expenses:Food: bread"));
+    }
+
+    /// Guards the default categorization pipeline against regressions
+    #[test]
+    fn test_default_pipeline_accuracy() {
+        let filters = FilterConfig::default();
+        let filter = filters.category_filter(None).unwrap().build();
+
+        let mut cm: CatStats = Trie::new();
+        for sample in read_dataset(&resource("categories_train.csv")).unwrap() {
+            assign_category(&filter(&sample.item), &sample.category, &mut cm);
+        }
+
+        let samples = read_dataset(&resource("categories_eval.csv")).unwrap();
+        let eval = evaluate(&samples, &filter, &cm, &HashSet::new());
+        assert_eq!(eval.precision(), 1.0, "{}", eval);
+        assert!(eval.coverage() >= 0.85, "{}", eval);
+    }
+
+    #[test]
+    fn test_read_dataset_error() {
+        assert!(read_dataset(Path::new("non_existing_file.csv")).is_err());
+
+        let path = std::env::temp_dir().join("receqif_short_dataset.csv");
+        std::fs::write(&path, "item\nmilk\n").unwrap();
+        let err = read_dataset(&path).unwrap_err();
+        assert_eq!(err.to_string(), "No category in the dataset line 2");
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,266 @@
+use crate::categories::{BoxedFilter, LineFilter};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use shellexpand::tilde;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// Configuration file name in the configuration directory
+pub const FILTERS_FILE: &str = "filters.json";
+
+/// Single step of the `LineFilter` pipeline
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "step", rename_all = "snake_case")]
+pub enum FilterStep {
+    /// Drop leading numbers
+    Numfilter,
+    /// Drop leading numbers and `*:` used in Perekrestok receipts
+    PerekrestokFilter,
+    /// Trim the default units from the end
+    TrimUnitsFromEnd,
+    /// Trim custom units from the end
+    TrimUnits { units: Vec<String> },
+    /// Convert to lower case
+    Casefold,
+    /// Replace Latin lookalikes in Cyrillic words
+    Homoglyphs,
+    /// Collapse runs of whitespace
+    CollapseWhitespace,
+    /// Strip quotes and punctuation
+    StripPunctuation,
+    /// All the normalization steps above
+    Normalize,
+    /// Keep only the normalized product part without brand and sizes
+    Product,
+    /// Replace every `pattern` match with `with`
+    Replace {
+        pattern: String,
+        #[serde(default)]
+        with: String,
+    },
+}
+
+/// Filters for memo lines and categorization keys
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Pipeline {
+    /// Applied to item names written into QIF memo
+    pub memo: Vec<FilterStep>,
+    /// Applied to item names before category lookup
+    pub category: Vec<FilterStep>,
+}
+
+/// Per-store replacement of the default pipelines
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct StorePipeline {
+    pub memo: Option<Vec<FilterStep>>,
+    pub category: Option<Vec<FilterStep>>,
+}
+
+/// Declarative description of all the filters
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FilterConfig {
+    #[serde(flatten)]
+    pub default: Pipeline,
+    /// Pipelines for stores which name contains the key
+    #[serde(default)]
+    pub stores: BTreeMap<String, StorePipeline>,
+}
+
+#[derive(Debug, Error)]
+pub enum FilterConfigError {
+    #[error("Can't read filters config: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Malformed filters config: {0}")]
+    Parse(#[from] serde_json::Error),
+    #[error("Wrong regex in filters config: {0}")]
+    Regex(#[from] regex::Error),
+}
+
+impl Default for FilterConfig {
+    /// The filters which used to be hardcoded, with categorization keys
+    /// normalized
+    fn default() -> Self {
+        FilterConfig {
+            default: Pipeline {
+                memo: vec![
+                    FilterStep::Numfilter,
+                    FilterStep::PerekrestokFilter,
+                    FilterStep::TrimUnitsFromEnd,
+                ],
+                category: vec![
+                    FilterStep::Numfilter,
+                    FilterStep::PerekrestokFilter,
+                    FilterStep::Normalize,
+                ],
+            },
+            stores: BTreeMap::new(),
+        }
+    }
+}
+
+/// Build a dynamic `LineFilter` out of `steps`
+pub fn build_filter(steps: &[FilterStep]) -> Result<LineFilter<BoxedFilter>, FilterConfigError> {
+    let mut filter = LineFilter::new().boxed();
+    for step in steps {
+        filter = match step {
+            FilterStep::Numfilter => filter.numfilter().boxed(),
+            FilterStep::PerekrestokFilter => filter.perekrestok_filter().boxed(),
+            FilterStep::TrimUnitsFromEnd => filter.trim_units_from_end().boxed(),
+            FilterStep::TrimUnits { units } => filter.trim_units(units.clone()).boxed(),
+            FilterStep::Casefold => filter.casefold().boxed(),
+            FilterStep::Homoglyphs => filter.homoglyphs().boxed(),
+            FilterStep::CollapseWhitespace => filter.collapse_whitespace().boxed(),
+            FilterStep::StripPunctuation => filter.strip_punctuation().boxed(),
+            FilterStep::Normalize => filter.normalize().boxed(),
+            FilterStep::Product => filter.product().boxed(),
+            FilterStep::Replace { pattern, with } => {
+                filter.replace(Regex::new(pattern)?, with.clone()).boxed()
+            }
+        }
+    }
+    Ok(filter)
+}
+
+impl FilterConfig {
+    /// Read the config from `path` and check that all the pipelines can be
+    /// built
+    pub fn load(path: &Path) -> Result<Self, FilterConfigError> {
+        let config: FilterConfig = serde_json::from_str(&fs::read_to_string(path)?)?;
+        config.memo_filter(None)?;
+        config.category_filter(None)?;
+        for store in config.stores.keys() {
+            config.memo_filter(Some(store))?;
+            config.category_filter(Some(store))?;
+        }
+        Ok(config)
+    }
+
+    /// Load the config from `path` or from the default location. Built-in
+    /// filters are used if no config is found.
+    pub fn load_or_default(path: &Option<String>) -> Result<Self, FilterConfigError> {
+        let path = match path {
+            Some(path) => PathBuf::from(tilde(path).as_ref()),
+            None => {
+                let default = PathBuf::from(
+                    tilde(&(crate::user::DEFAULT_DB_PATH.to_owned() + FILTERS_FILE)).as_ref(),
+                );
+                if !default.exists() {
+                    return Ok(FilterConfig::default());
+                }
+                default
+            }
+        };
+        FilterConfig::load(&path)
+    }
+
+    fn store_pipeline(&self, store: Option<&str>) -> Option<&StorePipeline> {
+        let store = store?.to_lowercase();
+        self.stores
+            .iter()
+            .find(|(name, _)| store.contains(&name.to_lowercase()))
+            .map(|(_, pipeline)| pipeline)
+    }
+
+    /// Filter for memo lines of items bought in `store`
+    pub fn memo_filter(
+        &self,
+        store: Option<&str>,
+    ) -> Result<LineFilter<BoxedFilter>, FilterConfigError> {
+        let steps = self
+            .store_pipeline(store)
+            .and_then(|p| p.memo.as_ref())
+            .unwrap_or(&self.default.memo);
+        build_filter(steps)
+    }
+
+    /// Filter for categorization keys of items bought in `store`
+    pub fn category_filter(
+        &self,
+        store: Option<&str>,
+    ) -> Result<LineFilter<BoxedFilter>, FilterConfigError> {
+        let steps = self
+            .store_pipeline(store)
+            .and_then(|p| p.category.as_ref())
+            .unwrap_or(&self.default.category);
+        build_filter(steps)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, remove_file};
+
+    const CONFIG: &str = r#"
+{
+  "memo": [
+    {"step": "numfilter"},
+    {"step": "trim_units", "units": ["кг", "шт"]}
+  ],
+  "category": [{"step": "perekrestok_filter"}, {"step": "casefold"}],
+  "stores": {
+    "ашан": {
+      "memo": [{"step": "replace", "pattern": "\\s+APP$"}]
+    }
+  }
+}
+"#;
+
+    #[test]
+    fn test_default_config() {
+        let config = FilterConfig::default();
+        let memo = config.memo_filter(None).unwrap().build();
+        assert_eq!(memo("123: *Milk 2 л"), "Milk");
+        let cat = config.category_filter(Some("Any")).unwrap().build();
+        assert_eq!(cat("123: *Milk  2 л"), "milk 2 л");
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let config: FilterConfig = serde_json::from_str(CONFIG).unwrap();
+        assert_eq!(
+            config.default.category,
+            vec![FilterStep::PerekrestokFilter, FilterStep::Casefold]
+        );
+
+        let memo = config.memo_filter(None).unwrap().build();
+        assert_eq!(memo("12 Apples 2кг"), "Apples");
+
+        let memo = config.memo_filter(Some("АШАН - Авиапарк")).unwrap().build();
+        assert_eq!(memo("СИДР 0.5 MAGNERS APP"), "СИДР 0.5 MAGNERS");
+
+        let cat = config
+            .category_filter(Some("АШАН - Авиапарк"))
+            .unwrap()
+            .build();
+        assert_eq!(cat("1: *Apples"), "apples");
+    }
+
+    #[test]
+    fn test_load_config() {
+        let dir = "/tmp/receqif_test/";
+        create_dir_all(dir).unwrap();
+        let path = PathBuf::from(format!("{}filters_load.json", dir));
+
+        fs::write(&path, CONFIG).unwrap();
+        assert!(FilterConfig::load(&path).is_ok());
+
+        fs::write(
+            &path,
+            r#"{"memo": [{"step": "replace", "pattern": "("}], "category": []}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            FilterConfig::load(&path),
+            Err(FilterConfigError::Regex(_))
+        ));
+
+        remove_file(&path).unwrap();
+        assert!(matches!(
+            FilterConfig::load(&path),
+            Err(FilterConfigError::Io(_))
+        ));
+    }
+}
@@ -1,21 +1,219 @@
+use crate::accounts::{AccountInfo, AccountKind, AccountTree};
 use csv::ReaderBuilder;
 use std::error::Error;
+use std::fs;
 use std::path::Path;
 
-/// Read accounts from GnuCash csv export file. Only EXPENSE accounts make
-/// sense, since they're used as transaction categories.
-pub fn read_accounts(path: &Path) -> Result<Vec<String>, Box<dyn Error>> {
+/// GnuCash boolean flag, exported as "T" or "F"
+fn gnucash_flag(value: &str) -> bool {
+    matches!(
+        value.trim().to_lowercase().as_str(),
+        "t" | "true" | "y" | "yes" | "1"
+    )
+}
+
+/// Read the account tree from GnuCash csv export file. Columns are looked
+/// up by header, type and full name fall back to the first two columns.
+pub fn read_account_tree(path: &Path) -> Result<AccountTree, Box<dyn Error>> {
     let mut rdr = ReaderBuilder::new().has_headers(true).from_path(path)?;
-    let mut result = Vec::<String>::new();
+    let headers = rdr.headers()?.clone();
+    let column = |names: &[&str]| {
+        headers
+            .iter()
+            .position(|h| names.iter().any(|n| h.trim().eq_ignore_ascii_case(n)))
+    };
+    let kind = column(&["Type"]).unwrap_or(0);
+    let name = column(&["Full Account Name", "full_name"]).unwrap_or(1);
+    let hidden = column(&["Hidden"]);
+    let placeholder = column(&["Placeholder", "place_holder"]);
+
+    let mut result = AccountTree::new();
     for e in rdr.records() {
         let record = e?;
-        if record[0].eq("EXPENSE") {
-            result.push(String::from(&record[1]));
+        let (Some(kind), Some(name)) = (record.get(kind), record.get(name)) else {
+            return Err(format!(
+                "No type or name in the accounts line {}",
+                record.position().map_or(0, |p| p.line())
+            )
+            .into());
+        };
+        let kind = match kind.parse::<AccountKind>() {
+            Ok(kind) => kind,
+            Err(e) => {
+                log::warn!("Skipping account {}: {}", name, e);
+                continue;
+            }
+        };
+        let flag =
+            |column: Option<usize>| column.and_then(|c| record.get(c)).is_some_and(gnucash_flag);
+        result.insert(AccountInfo {
+            hidden: flag(hidden),
+            placeholder: flag(placeholder),
+            ..AccountInfo::new(kind, name.trim())
+        });
+    }
+    Ok(result)
+}
+
+/// Account type from the hledger `type:` tag in the directive comment or
+/// from the top level account name
+fn journal_kind(name: &str, comment: &str) -> Option<AccountKind> {
+    comment
+        .split(',')
+        .filter_map(|tag| tag.trim().strip_prefix("type:"))
+        .find_map(AccountKind::from_hledger_type)
+        .or_else(|| AccountKind::from_name(name))
+}
+
+/// Read `account` directives from ledger or hledger journal
+pub fn read_ledger_accounts(path: &Path) -> Result<AccountTree, Box<dyn Error>> {
+    let content = fs::read_to_string(path)?;
+    let mut result = AccountTree::new();
+    for line in content.lines() {
+        let Some(rest) = line.strip_prefix("account ") else {
+            continue;
+        };
+        // Account names may contain single spaces, comments follow two
+        // spaces or a semicolon
+        let (name, comment) = match rest.split_once(';') {
+            Some((name, comment)) => (name, comment),
+            None => (rest, ""),
+        };
+        let name = name.split("  ").next().unwrap_or_default().trim();
+        if name.is_empty() {
+            continue;
+        }
+        match journal_kind(name, comment) {
+            Some(kind) => result.insert(AccountInfo::new(kind, name)),
+            None => log::warn!("Skipping account {}: unknown account type", name),
+        }
+    }
+    Ok(result)
+}
+
+/// Read `open` directives from beancount file, closed accounts are kept
+/// hidden
+pub fn read_beancount_accounts(path: &Path) -> Result<AccountTree, Box<dyn Error>> {
+    let content = fs::read_to_string(path)?;
+    let mut result = AccountTree::new();
+    let mut closed = Vec::<String>::new();
+    for line in content.lines() {
+        let line = line.split(';').next().unwrap_or_default();
+        let mut words = line.split_whitespace();
+        let (Some(_date), Some(directive), Some(name)) = (words.next(), words.next(), words.next())
+        else {
+            continue;
+        };
+        match directive {
+            "open" => match AccountKind::from_name(name) {
+                Some(kind) => result.insert(AccountInfo::new(kind, name)),
+                None => log::warn!("Skipping account {}: unknown account type", name),
+            },
+            "close" => closed.push(name.to_string()),
+            _ => (),
+        }
+    }
+    for name in closed {
+        if let Some(info) = result.get(&name) {
+            result.insert(AccountInfo {
+                hidden: true,
+                ..info.clone()
+            });
         }
     }
     Ok(result)
 }
 
+/// Read account tree from GnuCash csv export, ledger/hledger journal or
+/// beancount file, depending on file extension
+pub fn read_account_file(path: &Path) -> Result<AccountTree, Box<dyn Error>> {
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+    match ext.as_str() {
+        "beancount" | "bean" => read_beancount_accounts(path),
+        "ledger" | "journal" | "hledger" | "dat" => read_ledger_accounts(path),
+        _ => read_account_tree(path),
+    }
+}
+
+/// Strip QIF class from category and drop transfers to other accounts
+fn qif_category(category: &str) -> Option<String> {
+    let category = category.split('/').next().unwrap_or_default().trim();
+    if category.is_empty() || category.starts_with('[') {
+        None
+    } else {
+        Some(category.to_string())
+    }
+}
+
+/// Read memo and category pairs of transaction splits from QIF file
+pub fn read_qif_splits(path: &Path) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+    let content = fs::read_to_string(path)?;
+    let mut result = Vec::<(String, String)>::new();
+    let mut category: Option<String> = None;
+    for line in content.lines() {
+        let line = line.trim_end_matches('\r');
+        match line.chars().next() {
+            Some('S') => category = qif_category(&line[1..]),
+            Some('E') => {
+                if let Some(cat) = category.take() {
+                    let memo = line[1..].trim();
+                    if !memo.is_empty() {
+                        result.push((memo.to_string(), cat));
+                    }
+                }
+            }
+            Some('^') | Some('!') => category = None,
+            _ => (),
+        }
+    }
+    Ok(result)
+}
+
+/// Read memo and account pairs of splits from GnuCash csv transactions
+/// export. Splits without memo are skipped.
+pub fn read_gnucash_splits(path: &Path) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+    let mut rdr = ReaderBuilder::new().has_headers(true).from_path(path)?;
+    let headers = rdr.headers()?.clone();
+    let column = |names: &[&str]| {
+        headers
+            .iter()
+            .position(|h| names.iter().any(|n| h.trim().eq_ignore_ascii_case(n)))
+    };
+    let memo = column(&["Memo"]).ok_or("No Memo column in transactions export")?;
+    let account = column(&["Full Account Name", "Account Name"])
+        .ok_or("No account column in transactions export")?;
+
+    let mut result = Vec::<(String, String)>::new();
+    for e in rdr.records() {
+        let record = e?;
+        let (Some(memo), Some(account)) = (record.get(memo), record.get(account)) else {
+            return Err(format!(
+                "No memo or account in the transactions line {}",
+                record.position().map_or(0, |p| p.line())
+            )
+            .into());
+        };
+        let (memo, account) = (memo.trim(), account.trim());
+        if !memo.is_empty() && !account.is_empty() {
+            result.push((memo.to_string(), account.to_string()));
+        }
+    }
+    Ok(result)
+}
+
+/// Read item and category pairs from previously imported QIF or GnuCash
+/// transactions csv, depending on file extension
+pub fn read_history(path: &Path) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) if ext.eq_ignore_ascii_case("qif") => read_qif_splits(path),
+        _ => read_gnucash_splits(path),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -39,14 +237,165 @@ mod tests {
     #[test]
     fn test_read_accounts() {
         let file_path = create_test_file();
-        let accounts = read_accounts(&file_path).unwrap();
-        assert_eq!(accounts, vec!["Coffee", "Books"]);
+        let accounts = read_account_tree(&file_path).unwrap();
+        let categories: Vec<&str> = accounts.categories().map(|a| a.name.as_str()).collect();
+        assert_eq!(categories, vec!["Books", "Coffee"]);
+        assert_eq!(accounts.get("Bank").unwrap().kind, AccountKind::Asset);
+        remove_file(file_path).unwrap();
+    }
+
+    #[test]
+    fn test_read_gnucash_account_tree() {
+        let file_path = PathBuf::from("test_account_tree.csv");
+        fs::write(
+            &file_path,
+            "Type,Full Account Name,Account Name,Account Code,Description,Account Color,\
+             Notes,Symbol,Namespace,Hidden,Tax Info,Placeholder\n\
+             ASSET,Assets,Assets,,,,,RUB,CURRENCY,F,F,T\n\
+             BANK,Assets:Bank,Bank,,,,,RUB,CURRENCY,F,F,F\n\
+             CREDIT,Liabilities:Card,Card,,,,,RUB,CURRENCY,T,F,F\n\
+             EXPENSE,Expenses:Food,Food,,,,,RUB,CURRENCY,F,F,T\n\
+             EXPENSE,Expenses:Food:Bread,Bread,,,,,RUB,CURRENCY,F,F,F\n\
+             SAVINGS,Assets:Jar,Jar,,,,,RUB,CURRENCY,F,F,F\n",
+        )
+        .unwrap();
+        let tree = read_account_tree(&file_path).unwrap();
+        remove_file(&file_path).unwrap();
+
+        assert!(tree.get("Assets:Jar").is_none());
+        assert!(tree.get("Expenses:Food").unwrap().placeholder);
+        let bread = tree.get("Expenses:Food:Bread").unwrap();
+        assert_eq!(bread.parent.as_deref(), Some("Expenses:Food"));
+        assert!(!bread.placeholder && !bread.hidden);
+        let card = tree.get("Liabilities:Card").unwrap();
+        assert_eq!(card.kind, AccountKind::Credit);
+        assert!(card.hidden);
+        assert_eq!(tree.sources().count(), 3);
+    }
+
+    #[test]
+    fn test_read_ledger_accounts() {
+        let file_path = PathBuf::from("test_accounts.journal");
+        fs::write(
+            &file_path,
+            "; comment\n\
+             account Assets:Bank Account  ; type: C\n\
+             account Liabilities:Credit Card\n\
+             \tnote: the only card\n\
+             account Expenses:Food:Bread\n\
+             account Budget:Food\n\
+             2021-03-24 Auchan\n\
+             \tExpenses:Food:Bread  100 RUB\n\
+             \tAssets:Bank Account\n",
+        )
+        .unwrap();
+        let tree = read_account_file(&file_path).unwrap();
+        remove_file(&file_path).unwrap();
+
+        assert_eq!(
+            tree.get("Assets:Bank Account").unwrap().kind,
+            AccountKind::Cash
+        );
+        assert_eq!(
+            tree.get("Liabilities:Credit Card").unwrap().kind,
+            AccountKind::Liability
+        );
+        assert_eq!(
+            tree.categories()
+                .map(|a| a.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["Expenses:Food:Bread"]
+        );
+        assert!(tree.get("Budget:Food").is_none());
+    }
+
+    #[test]
+    fn test_read_beancount_accounts() {
+        let file_path = PathBuf::from("test_accounts.beancount");
+        fs::write(
+            &file_path,
+            "option \"operating_currency\" \"RUB\"\n\
+             2020-01-01 open Assets:Bank RUB\n\
+             2020-01-01 open Assets:OldCard RUB ; replaced\n\
+             2020-01-01 open Expenses:Food\n\
+             2021-01-01 close Assets:OldCard\n\
+             2021-03-24 * \"Auchan\"\n  Expenses:Food 100 RUB\n  Assets:Bank\n",
+        )
+        .unwrap();
+        let tree = read_account_file(&file_path).unwrap();
+        remove_file(&file_path).unwrap();
+
+        assert_eq!(tree.iter().count(), 3);
+        assert!(tree.get("Assets:OldCard").unwrap().hidden);
+        assert!(!tree.get("Assets:Bank").unwrap().hidden);
+        assert_eq!(tree.categories().count(), 1);
+    }
+
+    #[test]
+    fn test_read_qif_splits() {
+        let file_path = PathBuf::from("test_history.qif");
+        fs::write(
+            &file_path,
+            "!Account\nNWallet\nTCash\n^\n!Type:Cash\nD03/24/2021\nT-20.00\nMNew\n\
+             SExpenses:Food:Bread\nEХЛЕБ УКРАИНСКИЙ\n$-15.00\n\
+             S[Savings]\nEtransfer\n$-3.00\n\
+             SExpenses:Food/Class\nE\n$-1.00\n\
+             SExpenses:Food:Sauces\nEХРЕН РУССКИЙ\n$-1.00\n^\n",
+        )
+        .unwrap();
+        let splits = read_history(&file_path).unwrap();
+        assert_eq!(
+            splits,
+            vec![
+                (
+                    "ХЛЕБ УКРАИНСКИЙ".to_string(),
+                    "Expenses:Food:Bread".to_string()
+                ),
+                (
+                    "ХРЕН РУССКИЙ".to_string(),
+                    "Expenses:Food:Sauces".to_string()
+                ),
+            ]
+        );
+        remove_file(file_path).unwrap();
+    }
+
+    #[test]
+    fn test_read_gnucash_splits() {
+        let file_path = PathBuf::from("test_history.csv");
+        let mut file = File::create(&file_path).unwrap();
+        writeln!(
+            file,
+            "Date,Transaction ID,Description,Memo,Full Account Name,Amount Num."
+        )
+        .unwrap();
+        writeln!(file, "03/24/2021,abc,Auchan,,Assets:Wallet,-20").unwrap();
+        writeln!(file, ",,,СИДР 0.5 MAGNERS APP,Expenses:Food:Alcohol,17").unwrap();
+        writeln!(file, ",,,ХЛЕБ,Expenses:Food:Bread,3").unwrap();
+        drop(file);
+
+        let splits = read_history(&file_path).unwrap();
+        assert_eq!(splits.len(), 2);
+        assert_eq!(splits[0].1, "Expenses:Food:Alcohol");
+        remove_file(&file_path).unwrap();
+
+        fs::write(&file_path, "Date,Description\n03/24/2021,Auchan\n").unwrap();
+        assert!(read_history(&file_path).is_err());
+
+        fs::write(&file_path, "Memo,Full Account Name\nХЛЕБ\n").unwrap();
+        assert!(read_history(&file_path).is_err());
         remove_file(file_path).unwrap();
     }
 
     #[test]
     fn test_read_accounts_error() {
         let path = Path::new("non_existing_file.csv");
-        assert!(read_accounts(path).is_err());
+        assert!(read_account_tree(path).is_err());
+
+        let path = PathBuf::from("test_accounts_short.csv");
+        fs::write(&path, "Type\nEXPENSE\n").unwrap();
+        let err = read_account_tree(&path).unwrap_err();
+        assert_eq!(err.to_string(), "No type or name in the accounts line 2");
+        remove_file(path).unwrap();
     }
 }
@@ -0,0 +1,317 @@
+use crate::categories::{add_hits, CatStat, CatStats};
+use csv::{ReaderBuilder, WriterBuilder};
+use radix_trie::TrieCommon;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Current version of the exported knowledge format
+pub const KNOWLEDGE_VERSION: u32 = 1;
+
+/// Learned categories of a single item
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Entry {
+    pub item: String,
+    pub stats: Vec<CatStat>,
+}
+
+/// Category knowledge base detached from the user database
+///
+/// Only the category statistics are kept for now, there are no rules or
+/// payee mappings to carry along yet.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Knowledge {
+    pub version: u32,
+    pub catmap: Vec<Entry>,
+}
+
+/// How imported statistics are combined with the existing ones
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MergeMode {
+    /// Add imported hits to the existing ones
+    #[default]
+    Sum,
+    /// Imported statistics replace the existing ones for the same item
+    Replace,
+}
+
+impl FromStr for MergeMode {
+    type Err = KnowledgeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "sum" => Ok(MergeMode::Sum),
+            "replace" => Ok(MergeMode::Replace),
+            other => Err(KnowledgeError::Mode(other.to_string())),
+        }
+    }
+}
+
+/// Serialization format of the knowledge file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Versioned JSON document
+    Json,
+    /// Flat `item,category,hits` table
+    Csv,
+}
+
+impl Format {
+    /// Guess the format by the file extension, JSON is the default
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("csv") => Format::Csv,
+            _ => Format::Json,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum KnowledgeError {
+    #[error("Can't access knowledge file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Malformed knowledge JSON: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Malformed knowledge CSV: {0}")]
+    Csv(#[from] csv::Error),
+    #[error("Unsupported knowledge version {0}")]
+    Version(u32),
+    #[error("Unknown merge mode '{0}', use sum or replace")]
+    Mode(String),
+    #[error("Line {0} must have item, category and hits columns")]
+    Columns(u64),
+    #[error("Wrong hits '{1}' in line {0}, must be a positive number")]
+    Hits(u64, String),
+    #[error("Empty category in line {0}")]
+    Category(u64),
+    #[error("Wrong hits {1} for item '{0}', must be a positive number")]
+    ItemHits(String, i64),
+    #[error("Empty category for item '{0}'")]
+    ItemCategory(String),
+}
+
+impl Knowledge {
+    /// Snapshot of `storage`
+    pub fn from_catmap(storage: &CatStats) -> Self {
+        Knowledge {
+            version: KNOWLEDGE_VERSION,
+            catmap: storage
+                .iter()
+                .map(|(item, stats)| Entry {
+                    item: item.clone(),
+                    stats: stats.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Merge the knowledge into `storage`, returns number of affected items
+    pub fn merge_into(&self, storage: &mut CatStats, mode: MergeMode) -> usize {
+        for entry in &self.catmap {
+            if mode == MergeMode::Replace {
+                storage.remove(&entry.item);
+            }
+            for stat in &entry.stats {
+                if !stat.category().is_empty() {
+                    add_hits(&entry.item, stat.category(), stat.hits(), storage);
+                }
+            }
+        }
+        self.catmap.len()
+    }
+
+    /// Parse `data` in the given `format`
+    pub fn parse(data: &str, format: Format) -> Result<Self, KnowledgeError> {
+        match format {
+            Format::Json => {
+                let knowledge: Knowledge = serde_json::from_str(data)?;
+                if knowledge.version > KNOWLEDGE_VERSION {
+                    return Err(KnowledgeError::Version(knowledge.version));
+                }
+                for entry in &knowledge.catmap {
+                    for stat in &entry.stats {
+                        if stat.hits() <= 0 {
+                            return Err(KnowledgeError::ItemHits(entry.item.clone(), stat.hits()));
+                        }
+                        if stat.category().trim().is_empty() {
+                            return Err(KnowledgeError::ItemCategory(entry.item.clone()));
+                        }
+                    }
+                }
+                Ok(knowledge)
+            }
+            Format::Csv => {
+                let mut storage = CatStats::new();
+                let mut rdr = ReaderBuilder::new()
+                    .has_headers(true)
+                    .from_reader(data.as_bytes());
+                for e in rdr.records() {
+                    let record = e?;
+                    let line = record.position().map_or(0, |p| p.line());
+                    let (Some(item), Some(category), Some(hits)) =
+                        (record.get(0), record.get(1), record.get(2))
+                    else {
+                        return Err(KnowledgeError::Columns(line));
+                    };
+                    let hits = match hits.trim().parse::<i64>() {
+                        Ok(hits) if hits > 0 => hits,
+                        _ => return Err(KnowledgeError::Hits(line, hits.to_string())),
+                    };
+                    if category.trim().is_empty() {
+                        return Err(KnowledgeError::Category(line));
+                    }
+                    add_hits(item, category, hits, &mut storage);
+                }
+                Ok(Knowledge::from_catmap(&storage))
+            }
+        }
+    }
+
+    /// Serialize into the given `format`
+    pub fn render(&self, format: Format) -> Result<String, KnowledgeError> {
+        match format {
+            Format::Json => Ok(serde_json::to_string_pretty(self)?),
+            Format::Csv => {
+                let mut wtr = WriterBuilder::new().from_writer(vec![]);
+                wtr.write_record(["item", "category", "hits"])?;
+                for entry in &self.catmap {
+                    for stat in &entry.stats {
+                        wtr.write_record([
+                            entry.item.as_str(),
+                            stat.category(),
+                            &stat.hits().to_string(),
+                        ])?;
+                    }
+                }
+                let data = wtr.into_inner().map_err(|e| e.into_error())?;
+                Ok(String::from_utf8_lossy(&data).into_owned())
+            }
+        }
+    }
+
+    /// Read knowledge from `path`, the format is chosen by extension
+    pub fn read(path: &Path) -> Result<Self, KnowledgeError> {
+        Knowledge::parse(&fs::read_to_string(path)?, Format::from_path(path))
+    }
+
+    /// Write knowledge to `path`, the format is chosen by extension
+    pub fn write(&self, path: &Path) -> Result<(), KnowledgeError> {
+        fs::write(path, self.render(Format::from_path(path))?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::categories::{assign_category, get_top_category};
+    use std::fs::{create_dir_all, remove_file};
+    use std::path::PathBuf;
+
+    fn catmap() -> CatStats {
+        let mut cm = CatStats::new();
+        assign_category("milk", "Expenses:Dairy", &mut cm);
+        assign_category("milk", "Expenses:Dairy", &mut cm);
+        assign_category("milk", "Expenses:Food", &mut cm);
+        assign_category("bread", "Expenses:Food", &mut cm);
+        cm
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let knowledge = Knowledge::from_catmap(&catmap());
+        for format in [Format::Json, Format::Csv] {
+            let parsed = Knowledge::parse(&knowledge.render(format).unwrap(), format).unwrap();
+            let mut cm = CatStats::new();
+            assert_eq!(parsed.merge_into(&mut cm, MergeMode::Sum), 2);
+            assert_eq!(cm, catmap());
+        }
+    }
+
+    #[test]
+    fn test_merge_modes() {
+        let mut other = CatStats::new();
+        add_hits("milk", "Expenses:Food", 5, &mut other);
+        let knowledge = Knowledge::from_catmap(&other);
+
+        let mut cm = catmap();
+        knowledge.merge_into(&mut cm, MergeMode::Sum);
+        assert_eq!(get_top_category("milk", &cm), Some("Expenses:Food"));
+        assert_eq!(cm.get("milk").unwrap().len(), 2);
+        assert_eq!(cm.get("milk").unwrap()[0].hits(), 6);
+
+        let mut cm = catmap();
+        knowledge.merge_into(&mut cm, MergeMode::Replace);
+        assert_eq!(cm.get("milk").unwrap().len(), 1);
+        assert_eq!(get_top_category("bread", &cm), Some("Expenses:Food"));
+    }
+
+    #[test]
+    fn test_version_and_mode() {
+        assert!(matches!(
+            Knowledge::parse(r#"{"version": 99, "catmap": []}"#, Format::Json),
+            Err(KnowledgeError::Version(99))
+        ));
+        assert_eq!("Replace".parse::<MergeMode>().unwrap(), MergeMode::Replace);
+        assert!("prefer".parse::<MergeMode>().is_err());
+    }
+
+    #[test]
+    fn test_malformed_csv() {
+        assert!(matches!(
+            Knowledge::parse("item,category\nmilk,Expenses:Dairy\n", Format::Csv),
+            Err(KnowledgeError::Columns(2))
+        ));
+        for hits in ["many", "0", "-3"] {
+            let data = format!("item,category,hits\nmilk,Expenses:Dairy,{}\n", hits);
+            assert!(matches!(
+                Knowledge::parse(&data, Format::Csv),
+                Err(KnowledgeError::Hits(2, h)) if h == hits
+            ));
+        }
+        assert!(matches!(
+            Knowledge::parse("item,category,hits\nmilk, ,1\n", Format::Csv),
+            Err(KnowledgeError::Category(2))
+        ));
+    }
+
+    #[test]
+    fn test_malformed_json() {
+        let entry = |category: &str, hits: i64| {
+            format!(
+                r#"{{"version": 1, "catmap": [{{"item": "milk", "stats": [{{"category": "{}", "hits": {}}}]}}]}}"#,
+                category, hits
+            )
+        };
+        assert!(Knowledge::parse(&entry("Expenses:Dairy", 2), Format::Json).is_ok());
+        for hits in [0, -3] {
+            assert!(matches!(
+                Knowledge::parse(&entry("Expenses:Dairy", hits), Format::Json),
+                Err(KnowledgeError::ItemHits(item, h)) if item == "milk" && h == hits
+            ));
+        }
+        assert!(matches!(
+            Knowledge::parse(&entry("", 2), Format::Json),
+            Err(KnowledgeError::ItemCategory(item)) if item == "milk"
+        ));
+    }
+
+    #[test]
+    fn test_read_write() {
+        let dir = "/tmp/receqif_test/";
+        create_dir_all(dir).unwrap();
+        let path = PathBuf::from(format!("{}knowledge.csv", dir));
+        Knowledge::from_catmap(&catmap()).write(&path).unwrap();
+        assert!(fs::read_to_string(&path)
+            .unwrap()
+            .starts_with("item,category,hits\n"));
+        let mut cm = CatStats::new();
+        Knowledge::read(&path)
+            .unwrap()
+            .merge_into(&mut cm, MergeMode::Sum);
+        assert_eq!(cm, catmap());
+        remove_file(&path).unwrap();
+    }
+}
@@ -1,18 +1,27 @@
 use qif_generator::account::{Account, AccountType};
 
-use std::collections::HashSet;
 use std::path::PathBuf;
 use structopt::StructOpt;
 
+mod accounts;
 mod categories;
 mod convert;
+mod evaluate;
+mod filters;
 mod import;
+mod knowledge;
 #[cfg(feature = "monitoring")]
 mod monitoring;
+mod product;
 mod receipt;
+mod store;
 #[cfg(feature = "telegram")]
 mod telegram;
 #[cfg(feature = "telegram")]
+mod tgkeyboard;
+#[cfg(feature = "telegram")]
+mod tgstorage;
+#[cfg(feature = "telegram")]
 mod tgusermanager;
 mod ui;
 mod user;
@@ -20,12 +29,20 @@ mod user;
 /// Search for a pattern in a file and display the lines that contain it.
 #[derive(StructOpt)]
 struct Cli {
-    #[structopt(parse(from_os_str), long, help = "Accounts csv file")]
+    #[structopt(
+        parse(from_os_str),
+        long,
+        help = "Accounts from GnuCash csv, ledger/hledger journal or beancount file"
+    )]
     accounts: Option<PathBuf>,
 
     #[structopt(short, long)]
     database: Option<String>,
 
+    /// Filters config file
+    #[structopt(long)]
+    filters: Option<String>,
+
     #[structopt(long, default_value = "New")]
     memo: String,
 
@@ -39,8 +56,44 @@ struct Cli {
     #[structopt(long)]
     ui: bool,
 
+    /// Rename or merge category FROM into TO in the learned statistics
+    #[structopt(long, number_of_values = 2, value_names = &["FROM", "TO"])]
+    rename_category: Option<Vec<String>>,
+
+    /// List learned categories missing from the account list and offer remapping
+    #[structopt(long)]
+    stale_categories: bool,
+
+    /// Explain how the category for ITEM is chosen
+    #[structopt(long, value_name = "ITEM")]
+    explain: Option<String>,
+
+    /// Print product details and unit prices for the receipt items
+    #[structopt(long)]
+    unit_prices: bool,
+
+    /// Evaluate categorization over labelled csv with item,category columns
+    #[structopt(long, parse(from_os_str), value_name = "DATASET")]
+    evaluate: Option<PathBuf>,
+
+    /// Learn categories from QIF or GnuCash transactions csv export
+    #[structopt(long, parse(from_os_str), value_name = "HISTORY")]
+    learn: Option<PathBuf>,
+
+    /// Export learned categories to JSON or csv file
+    #[structopt(long, parse(from_os_str), value_name = "FILE")]
+    export_catmap: Option<PathBuf>,
+
+    /// Import learned categories from JSON or csv file
+    #[structopt(long, parse(from_os_str), value_name = "FILE")]
+    import_catmap: Option<PathBuf>,
+
+    /// How imported categories are merged: sum or replace
+    #[structopt(long, default_value = "sum")]
+    merge: knowledge::MergeMode,
+
     /// The path to the file to read
-    #[structopt(required_unless_one = &["telegram", "ui"])]
+    #[structopt(required_unless_one = &["telegram", "ui", "rename-category", "stale-categories", "explain", "evaluate", "learn", "export-catmap", "import-catmap"])]
     filename: Option<String>,
 
     /// Account name
@@ -52,6 +105,14 @@ struct Cli {
     account_type: AccountType,
 }
 
+/// Value of `result` or exit with the error printed
+fn or_exit<T, E: std::fmt::Display>(result: Result<T, E>) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    })
+}
+
 #[cfg(not(tarpaulin_include))]
 fn main() {
     pretty_env_logger::init();
@@ -59,17 +120,107 @@ fn main() {
     log::debug!("Log started");
     let args = Cli::from_args();
 
+    let filters = match filters::FilterConfig::load_or_default(&args.filters) {
+        Ok(filters) => filters,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
     #[cfg(feature = "telegram")]
     if args.telegram {
-        telegram::bot();
+        telegram::bot(filters);
         return;
     }
 
-    let mut user = user::User::new(0, &args.database);
+    let mut user = match user::User::new(0, &args.database, &filters) {
+        Ok(user) => user,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
 
     match args.accounts {
         None => (),
-        Some(path) => user.accounts(import::read_accounts(&path).unwrap()),
+        Some(path) => user.account_tree(or_exit(import::read_account_file(&path))),
+    }
+
+    if let Some(names) = &args.rename_category {
+        let affected = or_exit(user.rename_category(&names[0], &names[1]));
+        println!(
+            "{} items moved from {} to {}",
+            affected.len(),
+            names[0],
+            names[1]
+        );
+        for item in affected {
+            println!("{}", item);
+        }
+        return;
+    }
+
+    if args.stale_categories {
+        let stale = categories::stale_categories(&user.catmap, &user.accounts);
+        let istty = unsafe { libc::isatty(libc::STDOUT_FILENO) } != 0;
+        let cats: Vec<&String> = user.accounts.iter().collect();
+        let mut remap: Vec<(String, String)> = Vec::new();
+        for (category, items) in &stale {
+            println!("{}: {}", category, items.join(", "));
+            if istty {
+                let newcat = ui::input_category(category, "remap to", &cats);
+                if !newcat.is_empty() {
+                    remap.push((category.clone(), newcat));
+                }
+            }
+        }
+        // Only the stale category itself is moved, its children may be valid
+        for (from, to) in remap {
+            or_exit(user.remap_category(&from, &to));
+        }
+        return;
+    }
+
+    if let Some(item) = &args.explain {
+        let cat_filter = filters.category_filter(None).unwrap().build();
+        println!(
+            "{}",
+            categories::explain(item, cat_filter, &user.catmap, &user.accounts)
+        );
+        return;
+    }
+
+    if let Some(path) = &args.learn {
+        let pairs = or_exit(import::read_history(path));
+        let cat_filter = filters.category_filter(None).unwrap().build();
+        let learned = or_exit(user.learn(&pairs, cat_filter));
+        println!("Learned {} of {} splits", learned, pairs.len());
+        return;
+    }
+
+    if let Some(path) = &args.export_catmap {
+        let knowledge = user.export_knowledge();
+        or_exit(knowledge.write(path));
+        println!("Exported {} items", knowledge.catmap.len());
+        return;
+    }
+
+    if let Some(path) = &args.import_catmap {
+        let knowledge = or_exit(knowledge::Knowledge::read(path));
+        let merged = or_exit(user.import_knowledge(&knowledge, args.merge));
+        println!("Imported {} items", merged);
+        return;
+    }
+
+    if let Some(path) = &args.evaluate {
+        let samples = or_exit(evaluate::read_dataset(path));
+        let cat_filter = filters.category_filter(None).unwrap().build();
+        print!(
+            "{}",
+            evaluate::evaluate(&samples, cat_filter, &user.catmap, &user.accounts)
+        );
+        return;
     }
 
     #[cfg(feature = "tv")]
@@ -78,12 +229,6 @@ fn main() {
         return;
     }
 
-    let filter = categories::LineFilter::new()
-        .numfilter()
-        .perekrestok_filter()
-        .trim_units_from_end()
-        .build();
-
     // If program is used as command-line tool
     let acc = Account::new()
         .name(&args.account)
@@ -91,16 +236,40 @@ fn main() {
         .build();
 
     if let Some(filename) = &args.filename {
-        let cat_filter = categories::LineFilter::new()
-            .numfilter()
-            .perekrestok_filter()
-            .build();
-        let cat = &|item: &str,
-                    stats: &mut categories::CatStats,
-                    acc: &HashSet<String>|
-         -> String { categories::get_category(cat_filter(item), stats, acc) };
-        let t = convert::convert(filename, &args.memo, &mut user, &acc, filter, cat).unwrap();
+        let purchase = convert::read_file(filename);
+        if args.unit_prices {
+            for item in &purchase.items {
+                let name = product::parse(&item.name);
+                match (name.size, name.unit_price(item.sum, item.quantity)) {
+                    (Some(size), Some(price)) => {
+                        println!("{}: {:.2}/{}", name, price / 100.0, size.base_unit())
+                    }
+                    _ => println!("{}", name),
+                }
+            }
+            return;
+        }
+
+        let filter = filters.memo_filter(purchase.store()).unwrap().build();
+        let cat_filter = filters.category_filter(purchase.store()).unwrap().build();
+        let cat = &|item: &str, user: &mut user::User| -> String {
+            let key = categories::learned_key(item, cat_filter(item), &user.catmap).into_owned();
+            let (category, entered) = categories::get_category(&key, &user.catmap, &user.accounts);
+            if entered {
+                user.assign_category(&key, &category).unwrap_or_else(|err| {
+                    log::error!("Can't learn category of {} due to {:?}", key, err)
+                });
+            }
+            category
+        };
+        if user.is_processed(purchase.id()) {
+            eprintln!("Receipt {} was converted before", purchase.id());
+        }
+        let t = convert::convert(&purchase, &args.memo, &mut user, &acc, filter, cat).unwrap();
         print!("{}", acc);
         println!("{}", t);
+        user.mark_processed(purchase.id()).unwrap_or_else(|err| {
+            log::error!("Can't remember receipt {} due to {:?}", purchase.id(), err)
+        });
     }
 }
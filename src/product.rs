@@ -0,0 +1,319 @@
+use crate::categories::LineFilter;
+use regex::Regex;
+use std::fmt;
+use std::sync::OnceLock;
+
+/// Packaging size units
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unit {
+    Gram,
+    Kilogram,
+    Milliliter,
+    Liter,
+}
+
+impl Unit {
+    fn parse(unit: &str) -> Option<Self> {
+        match unit.to_lowercase().as_str() {
+            "г" | "гр" | "грамм" | "g" => Some(Unit::Gram),
+            "кг" | "kg" => Some(Unit::Kilogram),
+            "мл" | "ml" => Some(Unit::Milliliter),
+            "л" | "литр" | "l" => Some(Unit::Liter),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Unit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Unit::Gram => write!(f, "г"),
+            Unit::Kilogram => write!(f, "кг"),
+            Unit::Milliliter => write!(f, "мл"),
+            Unit::Liter => write!(f, "л"),
+        }
+    }
+}
+
+/// Weight or volume of a single package
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Size {
+    pub amount: f64,
+    pub unit: Unit,
+}
+
+impl Size {
+    /// Amount in kilograms or liters
+    pub fn base_amount(&self) -> f64 {
+        match self.unit {
+            Unit::Gram | Unit::Milliliter => self.amount / 1000.0,
+            Unit::Kilogram | Unit::Liter => self.amount,
+        }
+    }
+
+    /// Unit used by `base_amount`
+    pub fn base_unit(&self) -> Unit {
+        match self.unit {
+            Unit::Gram | Unit::Kilogram => Unit::Kilogram,
+            Unit::Milliliter | Unit::Liter => Unit::Liter,
+        }
+    }
+}
+
+impl fmt::Display for ProductName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.product)?;
+        if let Some(brand) = &self.brand {
+            write!(f, " [{}]", brand)?;
+        }
+        if let Some(pack) = self.pack {
+            write!(f, " {}x", pack)?;
+        }
+        if let Some(size) = self.size {
+            write!(f, " {}", size)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", self.amount, self.unit)
+    }
+}
+
+/// Item name split into parts
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProductName {
+    /// What is bought, e.g. "ХЛЕБ УКРАИНСКИЙ НАРЕЗКА"
+    pub product: String,
+    /// Brand if it can be told apart from the product
+    pub brand: Option<String>,
+    /// Size of a single package
+    pub size: Option<Size>,
+    /// Number of packages or pieces
+    pub pack: Option<u32>,
+}
+
+impl ProductName {
+    /// Normalized product name to be used as categorization key
+    pub fn key(&self) -> String {
+        LineFilter::new().normalize().build()(&self.product).into_owned()
+    }
+
+    /// Price per kilogram or liter for `sum` paid for `quantity` of the
+    /// item, in the same currency units as `sum`
+    pub fn unit_price(&self, sum: i64, quantity: f64) -> Option<f64> {
+        let size = self.size?;
+        let total = size.base_amount() * self.pack.unwrap_or(1) as f64 * quantity;
+        if total > 0.0 {
+            Some(sum as f64 / total)
+        } else {
+            None
+        }
+    }
+}
+
+const UNITS: &str = "кг|kg|грамм|гр|г|g|мл|ml|литр|л|l";
+
+fn quoted_re() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| Regex::new(r#"["«“„]([^"«»“”„]+)["»”“]"#).unwrap())
+}
+
+fn multipack_re() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| {
+        Regex::new(&format!(
+            r"(?i)\b(\d+)\s*[xх*]\s*(\d+(?:[.,]\d+)?)\s*({})\b",
+            UNITS
+        ))
+        .unwrap()
+    })
+}
+
+fn size_re() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| Regex::new(&format!(r"(?i)\b(\d+(?:[.,]\d+)?)\s*({})\b", UNITS)).unwrap())
+}
+
+fn pieces_re() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| Regex::new(r"(?i)\b(\d+)\s*(?:шт|pcs)\b").unwrap())
+}
+
+fn bare_decimal_re() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| Regex::new(r"\b(0[.,]\d{1,3})\b").unwrap())
+}
+
+fn trailing_number_re() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| Regex::new(r"\s(\d{3})\s*$").unwrap())
+}
+
+fn parse_amount(amount: &str) -> Option<f64> {
+    amount.replace(',', ".").parse().ok()
+}
+
+/// Drop punctuation from the ends, leftover unit markers and collapse spaces
+fn cleanup(part: &str) -> String {
+    part.split_whitespace()
+        .map(|w| w.trim_matches(|c: char| c.is_ascii_punctuation() && c != '%'))
+        .filter(|w| !w.is_empty() && !["шт", "pcs", "вес"].contains(&w.to_lowercase().as_str()))
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+/// Split the product name at the match and keep the text around it
+fn split_at_match(name: &str, start: usize, end: usize) -> (String, String) {
+    (name[..start].to_string(), name[end..].to_string())
+}
+
+/// Split receipt item `name` into product, brand, size and pack count
+pub fn parse(name: &str) -> ProductName {
+    let mut brand: Option<String> = None;
+    let mut text = name.to_string();
+    if let Some(caps) = quoted_re().captures(name) {
+        brand = Some(caps[1].trim().to_string());
+        text = quoted_re().replace(name, " ").into_owned();
+    }
+
+    let mut size = None;
+    let mut pack = None;
+    let (head, tail) = if let Some(caps) = multipack_re().captures(&text) {
+        pack = caps[1].parse().ok();
+        size = parse_amount(&caps[2])
+            .and_then(|amount| Unit::parse(&caps[3]).map(|unit| Size { amount, unit }));
+        let m = caps.get(0).unwrap();
+        split_at_match(&text, m.start(), m.end())
+    } else if let Some(caps) = size_re().captures(&text) {
+        size = parse_amount(&caps[1])
+            .and_then(|amount| Unit::parse(&caps[2]).map(|unit| Size { amount, unit }));
+        let m = caps.get(0).unwrap();
+        split_at_match(&text, m.start(), m.end())
+    } else if let Some(caps) = pieces_re().captures(&text) {
+        pack = caps[1].parse().ok();
+        let m = caps.get(0).unwrap();
+        split_at_match(&text, m.start(), m.end())
+    } else if let Some(caps) = bare_decimal_re().captures(&text) {
+        // Bare fractions without units are drink volumes, e.g. "СИДР 0.5"
+        size = parse_amount(&caps[1]).map(|amount| Size {
+            amount,
+            unit: Unit::Liter,
+        });
+        let m = caps.get(0).unwrap();
+        split_at_match(&text, m.start(), m.end())
+    } else if let Some(caps) = trailing_number_re().captures(&text) {
+        // Stores tend to omit grams at the end, e.g. "ЧЕРРИ 250"
+        size = caps[1].parse().ok().map(|amount| Size {
+            amount,
+            unit: Unit::Gram,
+        });
+        let m = caps.get(0).unwrap();
+        split_at_match(&text, m.start(), m.end())
+    } else {
+        (text.clone(), String::new())
+    };
+
+    let mut product = cleanup(&head);
+    let tail = cleanup(&tail);
+    if !tail.is_empty() {
+        brand = Some(match brand {
+            Some(b) => format!("{} {}", b, tail),
+            None => tail,
+        });
+    }
+
+    // Latin words after a Cyrillic product name are usually the brand
+    if brand.is_none()
+        && product
+            .chars()
+            .any(|c| matches!(c, '\u{0400}'..='\u{04FF}'))
+    {
+        let words: Vec<&str> = product.split(' ').collect();
+        let latin = words
+            .iter()
+            .rev()
+            .take_while(|w| w.chars().all(|c| c.is_ascii_alphabetic()))
+            .count();
+        if latin > 0 && latin < words.len() {
+            brand = Some(words[words.len() - latin..].join(" "));
+            product = words[..words.len() - latin].join(" ");
+        }
+    }
+
+    ProductName {
+        product,
+        brand,
+        size,
+        pack,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use csv::ReaderBuilder;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_corpus() {
+        let mut p = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        p.push("tests/resources/item_names.csv");
+        let mut rdr = ReaderBuilder::new().has_headers(true).from_path(p).unwrap();
+
+        for record in rdr.records() {
+            let record = record.unwrap();
+            let parsed = parse(&record[0]);
+            assert_eq!(parsed.product, &record[1], "product of {}", &record[0]);
+            assert_eq!(
+                parsed.brand.as_deref().unwrap_or(""),
+                &record[2],
+                "brand of {}",
+                &record[0]
+            );
+            match parsed.size {
+                Some(size) => {
+                    let amount: f64 = record[3].parse().unwrap();
+                    assert!(
+                        (size.amount - amount).abs() < 1e-9,
+                        "size of {}",
+                        &record[0]
+                    );
+                    assert_eq!(size.unit.to_string(), &record[4], "unit of {}", &record[0]);
+                }
+                None => assert!(record[3].is_empty(), "no size for {}", &record[0]),
+            }
+            assert_eq!(
+                parsed.pack.map(|p| p.to_string()).unwrap_or_default(),
+                &record[5],
+                "pack of {}",
+                &record[0]
+            );
+        }
+    }
+
+    #[test]
+    fn test_key() {
+        assert_eq!(parse("СИДР 0.5 MAGNERS APP").key(), "сидр");
+        assert_eq!(parse("Bread 300г Extra").key(), parse("BREAD 500г").key());
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(
+            parse("ВОДА МИН. 6X1.5Л \"СВЯТОЙ ИСТОЧНИК\"").to_string(),
+            "ВОДА МИН [СВЯТОЙ ИСТОЧНИК] 6x 1.5л"
+        );
+    }
+
+    #[test]
+    fn test_unit_price() {
+        let water = parse("ВОДА МИН. 6X1.5Л");
+        assert_eq!(water.unit_price(108000, 2.0), Some(6000.0));
+        let bread = parse("ХЛЕБ УКРАИНСКИЙ НАРЕЗКА 650Г");
+        assert!((bread.unit_price(3250, 1.0).unwrap() - 5000.0).abs() < 1e-6);
+        assert_eq!(parse("ЛИМОНЫ КОРОБКА ВЕС").unit_price(100, 1.0), None);
+    }
+}
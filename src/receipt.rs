@@ -1,14 +1,27 @@
 use chrono::{DateTime, Utc};
 use serde::Deserialize;
 use std::fmt;
+use thiserror::Error;
 
 pub struct Purchase {
+    id: String,
     sum: i64,
     date: DateTime<Utc>,
+    store: Option<String>,
     pub items: Vec<Item>,
 }
 
 impl Purchase {
+    /// Fiscal identifiers of the receipt, or date and sum if they're missing
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Retail place or seller name if present in receipt
+    pub fn store(&self) -> Option<&str> {
+        self.store.as_deref()
+    }
+
     pub fn total_sum(&self) -> i64 {
         self.sum
     }
@@ -22,6 +35,12 @@ impl Purchase {
 pub struct Item {
     pub name: String,
     pub sum: i64,
+    #[serde(default = "default_quantity")]
+    pub quantity: f64,
+}
+
+fn default_quantity() -> f64 {
+    1.0
 }
 
 impl fmt::Display for Item {
@@ -37,6 +56,11 @@ struct Receipt {
     totalSum: i64,
     #[serde(with = "custom_date_format")]
     dateTime: DateTime<Utc>,
+    retailPlace: Option<String>,
+    user: Option<String>,
+    fiscalDriveNumber: Option<String>,
+    fiscalDocumentNumber: Option<i64>,
+    fiscalSign: Option<i64>,
     pub items: Vec<Item>,
 }
 
@@ -78,14 +102,44 @@ struct Input {
     ticket: Ticket,
 }
 
-pub fn parse_purchase(line: &str) -> Purchase {
-    // TODO: Check if several receipts are possible
-    let receipt: Vec<Input> = serde_json::from_str(line).unwrap();
-    Purchase {
-        sum: receipt[0].ticket.document.receipt.totalSum,
-        date: receipt[0].ticket.document.receipt.dateTime,
-        items: receipt[0].ticket.document.receipt.items.clone(),
+/// Why the data is not a receipt export
+#[derive(Debug, Error)]
+pub enum ReceiptError {
+    #[error("The file is not a list of receipts")]
+    NotList,
+    #[error("The file has no receipts")]
+    Empty,
+    #[error("Unexpected receipt format: {0}")]
+    Format(#[from] serde_json::Error),
+}
+
+/// Parse the receipt export, checking its shape first
+pub fn try_parse_purchase(line: &str) -> Result<Purchase, ReceiptError> {
+    // Cheap check to reject other JSON documents and binary files early
+    if !line.trim_start().starts_with('[') {
+        return Err(ReceiptError::NotList);
     }
+    // TODO: Check if several receipts are possible
+    let receipt: Vec<Input> = serde_json::from_str(line)?;
+    let r = match receipt.first() {
+        Some(input) => &input.ticket.document.receipt,
+        None => return Err(ReceiptError::Empty),
+    };
+    let id = match (&r.fiscalDriveNumber, r.fiscalDocumentNumber, r.fiscalSign) {
+        (Some(fn_), Some(fd), Some(fp)) => format!("{}:{}:{}", fn_, fd, fp),
+        _ => format!("{}:{}", r.dateTime.to_rfc3339(), r.totalSum),
+    };
+    Ok(Purchase {
+        id,
+        sum: r.totalSum,
+        date: r.dateTime,
+        store: r.retailPlace.clone().or_else(|| r.user.clone()),
+        items: r.items.clone(),
+    })
+}
+
+pub fn parse_purchase(line: &str) -> Purchase {
+    try_parse_purchase(line).unwrap()
 }
 
 #[cfg(test)]
@@ -112,6 +166,7 @@ mod receipttest {
         let testit: Item = serde_json::from_str(&line).unwrap();
         assert_eq!(testit.name, "ХРЕН РУССКИЙ 170Г");
         assert_eq!(testit.sum, 5549);
+        assert_eq!(testit.quantity, 1.0);
     }
 
     #[test]
@@ -211,11 +266,32 @@ mod receipttest {
         let it = Item {
             name: "test".to_string(),
             sum: 1000,
+            quantity: 1.0,
         };
         let line = it.to_string();
         assert_eq!(line, "test:1000");
     }
 
+    #[test]
+    fn sniff() {
+        assert!(matches!(
+            try_parse_purchase(r#"{"ticket": {}}"#),
+            Err(ReceiptError::NotList)
+        ));
+        assert!(matches!(
+            try_parse_purchase("\u{7f}ELF"),
+            Err(ReceiptError::NotList)
+        ));
+        assert!(matches!(
+            try_parse_purchase(" []"),
+            Err(ReceiptError::Empty)
+        ));
+        assert!(matches!(
+            try_parse_purchase(r#"[{"ticket": {}}]"#),
+            Err(ReceiptError::Format(_))
+        ));
+    }
+
     #[ignore]
     #[test]
     fn found_failure() {
@@ -0,0 +1,732 @@
+use crate::accounts::{AccountInfo, AccountKind, AccountTree};
+use crate::categories::{add_hits, CatStats};
+use crate::filters::FilterConfig;
+use pickledb::{PickleDb, PickleDbDumpPolicy, SerializationMethod};
+use radix_trie::TrieCommon;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use std::collections::HashSet;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum StoreError {
+    #[error("SQLite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("PickleDb error: {0}")]
+    Pickle(#[from] pickledb::error::Error),
+    #[error("Malformed stored value: {0}")]
+    Value(String),
+    #[error("Can't back the database up: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Database schema version {0} is newer than supported")]
+    Version(u32),
+    #[error("Can't build the filters to migrate with: {0}")]
+    Filters(String),
+}
+
+impl StoreError {
+    /// Whether the data can't be read because of its content
+    pub fn is_corrupt(&self) -> bool {
+        match self {
+            StoreError::Sqlite(rusqlite::Error::SqliteFailure(e, _)) => matches!(
+                e.code,
+                rusqlite::ErrorCode::NotADatabase | rusqlite::ErrorCode::DatabaseCorrupt
+            ),
+            StoreError::Pickle(e) => {
+                matches!(e.get_type(), pickledb::error::ErrorType::Serialization)
+            }
+            StoreError::Value(_) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Current version of the user data layout
+pub const SCHEMA_VERSION: u32 = 3;
+
+/// Persistent storage of a single user data
+pub trait UserStore: Send {
+    fn load_catmap(&self) -> Result<CatStats, StoreError>;
+
+    /// Replace all the stored statistics with `catmap` at once, for bulk
+    /// changes like rename or import
+    fn save_catmap(&mut self, catmap: &CatStats) -> Result<(), StoreError>;
+
+    /// Count `hits` more for `category` of `item` on top of the stored ones
+    fn add_hits(&mut self, item: &str, category: &str, hits: i64) -> Result<(), StoreError>;
+
+    fn load_accounts(&self) -> Result<(HashSet<String>, AccountTree), StoreError>;
+
+    /// Replace the category set and account tree at once
+    fn save_accounts(
+        &mut self,
+        accounts: &HashSet<String>,
+        tree: &AccountTree,
+    ) -> Result<(), StoreError>;
+
+    fn setting(&self, key: &str) -> Result<Option<String>, StoreError>;
+
+    fn set_setting(&mut self, key: &str, value: &str) -> Result<(), StoreError>;
+
+    /// Forget the setting `key`, missing settings are fine
+    fn remove_setting(&mut self, key: &str) -> Result<(), StoreError>;
+
+    /// Whether receipt with `id` was converted before
+    fn is_processed(&self, id: &str) -> Result<bool, StoreError>;
+
+    fn mark_processed(&mut self, id: &str) -> Result<(), StoreError>;
+
+    /// Write pending changes down
+    fn flush(&mut self) -> Result<(), StoreError>;
+
+    /// Layout version of the stored data, unversioned databases are 1
+    fn schema_version(&self) -> Result<u32, StoreError>;
+
+    fn set_schema_version(&mut self, version: u32) -> Result<(), StoreError>;
+}
+
+/// Migrations get the active filters, the learned keys depend on them
+type Migration = fn(&mut dyn UserStore, &FilterConfig) -> Result<(), StoreError>;
+
+/// Migrations with the versions they lead to, in order
+const MIGRATIONS: [(u32, Migration); 2] = [(2, typed_accounts), (3, normalized_keys)];
+
+/// Account tree appeared in version 2, plain category lists become
+/// expense accounts
+fn typed_accounts(store: &mut dyn UserStore, _filters: &FilterConfig) -> Result<(), StoreError> {
+    let (accounts, mut tree) = store.load_accounts()?;
+    for name in &accounts {
+        if tree.get(name).is_none() {
+            tree.insert(AccountInfo::new(AccountKind::Expense, name));
+        }
+    }
+    store.save_accounts(&accounts, &tree)
+}
+
+/// Categories are looked up by normalized names since version 3, so merge
+/// the statistics learned for different spellings. Keys are built with the
+/// category pipeline of `filters` used for lookups, the store the items
+/// were bought in is not known.
+fn normalized_keys(store: &mut dyn UserStore, filters: &FilterConfig) -> Result<(), StoreError> {
+    let normalize = filters
+        .category_filter(None)
+        .map_err(|e| StoreError::Filters(e.to_string()))?
+        .build();
+    let mut catmap = CatStats::new();
+    for (item, stats) in store.load_catmap()?.iter() {
+        let key = normalize(item);
+        for stat in stats {
+            add_hits(&key, stat.category(), stat.hits(), &mut catmap);
+        }
+    }
+    store.save_catmap(&catmap)
+}
+
+/// File the database at `path` is copied to before migrating from `version`
+pub fn backup_path(path: &Path, version: u32) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".v{}.bak", version));
+    PathBuf::from(name)
+}
+
+/// Bring `store` opened from `path` to `SCHEMA_VERSION`, backing the file
+/// up first
+fn migrate(
+    path: &Path,
+    store: &mut dyn UserStore,
+    filters: &FilterConfig,
+) -> Result<(), StoreError> {
+    let version = store.schema_version()?;
+    if version > SCHEMA_VERSION {
+        return Err(StoreError::Version(version));
+    }
+    if version == SCHEMA_VERSION {
+        return Ok(());
+    }
+
+    let backup = backup_path(path, version);
+    fs::copy(path, &backup)?;
+    log::info!(
+        "Migrating {} from version {} to {}, backup saved to {}",
+        path.display(),
+        version,
+        SCHEMA_VERSION,
+        backup.display()
+    );
+    for (target, migration) in MIGRATIONS.iter().filter(|(v, _)| *v > version) {
+        migration(store, filters)?;
+        store.set_schema_version(*target)?;
+    }
+    store.flush()
+}
+
+const SQLITE_HEADER: &[u8] = b"SQLite format 3\0";
+
+/// Whether `path` is an existing pickledb file
+fn is_legacy(path: &Path) -> bool {
+    let mut header = Vec::new();
+    fs::File::open(path)
+        .and_then(|f| f.take(SQLITE_HEADER.len() as u64).read_to_end(&mut header))
+        .is_ok_and(|len| len > 0 && header != SQLITE_HEADER)
+}
+
+/// Open the store at `path`. Existing pickledb files are opened with the
+/// legacy backend, everything else is SQLite. Older databases are migrated
+/// with the active `filters`.
+pub fn open(path: &Path, filters: &FilterConfig) -> Result<Box<dyn UserStore>, StoreError> {
+    let legacy = is_legacy(path);
+    let existed = path.exists();
+    let mut store: Box<dyn UserStore> = if legacy {
+        Box::new(PickleStore::open(path)?)
+    } else {
+        Box::new(SqliteStore::open(path)?)
+    };
+    if existed {
+        migrate(path, store.as_mut(), filters)?;
+    } else {
+        store.set_schema_version(SCHEMA_VERSION)?;
+    }
+    Ok(store)
+}
+
+/// Open the existing store at `path` for reading only, it's neither created
+/// nor migrated. Changes are never written down.
+pub fn open_read_only(path: &Path) -> Result<Box<dyn UserStore>, StoreError> {
+    if is_legacy(path) {
+        let db = PickleDb::load_read_only(path, SerializationMethod::Json)?;
+        Ok(Box::new(PickleStore { db }))
+    } else {
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        conn.busy_timeout(Duration::from_secs(5))?;
+        Ok(Box::new(SqliteStore { conn }))
+    }
+}
+
+/// User data in SQLite database, every save is a single transaction
+pub struct SqliteStore {
+    conn: Connection,
+}
+
+const SQLITE_SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS catmap (
+    item TEXT NOT NULL,
+    category TEXT NOT NULL,
+    hits INTEGER NOT NULL,
+    PRIMARY KEY (item, category)
+);
+CREATE TABLE IF NOT EXISTS accounts (
+    name TEXT PRIMARY KEY
+);
+CREATE TABLE IF NOT EXISTS account_tree (
+    name TEXT PRIMARY KEY,
+    kind TEXT NOT NULL,
+    parent TEXT,
+    hidden INTEGER NOT NULL,
+    placeholder INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS settings (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS receipts (
+    id TEXT PRIMARY KEY,
+    processed_at TEXT NOT NULL
+);
+";
+
+impl SqliteStore {
+    pub fn open(path: &Path) -> Result<Self, StoreError> {
+        let conn = Connection::open(path)?;
+        // Another process may hold the lock while saving
+        conn.busy_timeout(Duration::from_secs(5))?;
+        conn.execute_batch(SQLITE_SCHEMA)?;
+        Ok(SqliteStore { conn })
+    }
+}
+
+impl UserStore for SqliteStore {
+    fn load_catmap(&self) -> Result<CatStats, StoreError> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT item, category, hits FROM catmap")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, i64>(2)?,
+            ))
+        })?;
+        let mut catmap = CatStats::new();
+        for row in rows {
+            let (item, category, hits) = row?;
+            add_hits(&item, &category, hits, &mut catmap);
+        }
+        Ok(catmap)
+    }
+
+    fn save_catmap(&mut self, catmap: &CatStats) -> Result<(), StoreError> {
+        let tx = self.conn.transaction()?;
+        tx.execute("DELETE FROM catmap", [])?;
+        {
+            let mut stmt =
+                tx.prepare("INSERT INTO catmap (item, category, hits) VALUES (?1, ?2, ?3)")?;
+            for (item, stats) in catmap.iter() {
+                for stat in stats {
+                    stmt.execute(params![item, stat.category(), stat.hits()])?;
+                }
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn add_hits(&mut self, item: &str, category: &str, hits: i64) -> Result<(), StoreError> {
+        // Concurrent sessions add their hits up instead of overwriting
+        self.conn.execute(
+            "INSERT INTO catmap (item, category, hits) VALUES (?1, ?2, ?3)
+             ON CONFLICT(item, category) DO UPDATE SET hits = hits + excluded.hits",
+            params![item, category, hits],
+        )?;
+        Ok(())
+    }
+
+    fn load_accounts(&self) -> Result<(HashSet<String>, AccountTree), StoreError> {
+        let mut stmt = self.conn.prepare("SELECT name FROM accounts")?;
+        let accounts = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<HashSet<String>, _>>()?;
+
+        let mut stmt = self
+            .conn
+            .prepare("SELECT name, kind, parent, hidden, placeholder FROM account_tree")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, Option<String>>(2)?,
+                row.get::<_, bool>(3)?,
+                row.get::<_, bool>(4)?,
+            ))
+        })?;
+        let mut tree = AccountTree::new();
+        for row in rows {
+            let (name, kind, parent, hidden, placeholder) = row?;
+            tree.insert(AccountInfo {
+                kind: kind.parse().map_err(StoreError::Value)?,
+                name,
+                parent,
+                hidden,
+                placeholder,
+            });
+        }
+        Ok((accounts, tree))
+    }
+
+    fn save_accounts(
+        &mut self,
+        accounts: &HashSet<String>,
+        tree: &AccountTree,
+    ) -> Result<(), StoreError> {
+        let tx = self.conn.transaction()?;
+        tx.execute("DELETE FROM accounts", [])?;
+        tx.execute("DELETE FROM account_tree", [])?;
+        {
+            let mut stmt = tx.prepare("INSERT INTO accounts (name) VALUES (?1)")?;
+            for name in accounts {
+                stmt.execute([name])?;
+            }
+            let mut stmt = tx.prepare(
+                "INSERT INTO account_tree (name, kind, parent, hidden, placeholder)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for info in tree.iter() {
+                stmt.execute(params![
+                    info.name,
+                    info.kind.to_string(),
+                    info.parent,
+                    info.hidden,
+                    info.placeholder
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn setting(&self, key: &str) -> Result<Option<String>, StoreError> {
+        Ok(self
+            .conn
+            .query_row("SELECT value FROM settings WHERE key = ?1", [key], |row| {
+                row.get(0)
+            })
+            .optional()?)
+    }
+
+    fn set_setting(&mut self, key: &str, value: &str) -> Result<(), StoreError> {
+        self.conn.execute(
+            "INSERT INTO settings (key, value) VALUES (?1, ?2)
+             ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+            [key, value],
+        )?;
+        Ok(())
+    }
+
+    fn remove_setting(&mut self, key: &str) -> Result<(), StoreError> {
+        self.conn
+            .execute("DELETE FROM settings WHERE key = ?1", [key])?;
+        Ok(())
+    }
+
+    fn is_processed(&self, id: &str) -> Result<bool, StoreError> {
+        Ok(self
+            .conn
+            .query_row("SELECT 1 FROM receipts WHERE id = ?1", [id], |_| Ok(()))
+            .optional()?
+            .is_some())
+    }
+
+    fn mark_processed(&mut self, id: &str) -> Result<(), StoreError> {
+        self.conn.execute(
+            "INSERT OR IGNORE INTO receipts (id, processed_at) VALUES (?1, ?2)",
+            [id, &chrono::Utc::now().to_rfc3339()],
+        )?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), StoreError> {
+        // Every change is committed right away
+        Ok(())
+    }
+
+    fn schema_version(&self) -> Result<u32, StoreError> {
+        let version: u32 = self
+            .conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        Ok(version.max(1))
+    }
+
+    fn set_schema_version(&mut self, version: u32) -> Result<(), StoreError> {
+        self.conn
+            .execute_batch(&format!("PRAGMA user_version = {}", version))?;
+        Ok(())
+    }
+}
+
+/// User data in pickledb JSON file, kept for the existing databases
+pub struct PickleStore {
+    db: PickleDb,
+}
+
+impl PickleStore {
+    pub fn open(path: &Path) -> Result<Self, StoreError> {
+        let db = PickleDb::load(
+            path,
+            PickleDbDumpPolicy::PeriodicDump(Duration::from_secs(10)),
+            SerializationMethod::Json,
+        )?;
+        Ok(PickleStore { db })
+    }
+
+    /// Stored value or default if the key is missing. PickleDb returns None
+    /// for malformed values as well, they must not be mistaken for empty.
+    fn get_or_default<T>(&self, key: &str) -> Result<T, StoreError>
+    where
+        T: serde::de::DeserializeOwned + Default,
+    {
+        if !self.db.exists(key) {
+            return Ok(T::default());
+        }
+        self.db
+            .get(key)
+            .ok_or_else(|| StoreError::Value(format!("can't read {}", key)))
+    }
+}
+
+impl UserStore for PickleStore {
+    fn load_catmap(&self) -> Result<CatStats, StoreError> {
+        self.get_or_default("catmap")
+    }
+
+    fn save_catmap(&mut self, catmap: &CatStats) -> Result<(), StoreError> {
+        Ok(self.db.set("catmap", catmap)?)
+    }
+
+    fn add_hits(&mut self, item: &str, category: &str, hits: i64) -> Result<(), StoreError> {
+        let mut catmap = self.load_catmap()?;
+        add_hits(item, category, hits, &mut catmap);
+        self.save_catmap(&catmap)
+    }
+
+    fn load_accounts(&self) -> Result<(HashSet<String>, AccountTree), StoreError> {
+        let accounts: Vec<String> = self.get_or_default("accounts")?;
+        Ok((
+            HashSet::from_iter(accounts),
+            self.get_or_default("account_tree")?,
+        ))
+    }
+
+    fn save_accounts(
+        &mut self,
+        accounts: &HashSet<String>,
+        tree: &AccountTree,
+    ) -> Result<(), StoreError> {
+        self.db.set("accounts", accounts)?;
+        Ok(self.db.set("account_tree", tree)?)
+    }
+
+    fn setting(&self, key: &str) -> Result<Option<String>, StoreError> {
+        Ok(self.db.get(&format!("setting:{}", key)))
+    }
+
+    fn set_setting(&mut self, key: &str, value: &str) -> Result<(), StoreError> {
+        Ok(self.db.set(&format!("setting:{}", key), &value)?)
+    }
+
+    fn remove_setting(&mut self, key: &str) -> Result<(), StoreError> {
+        self.db.rem(&format!("setting:{}", key))?;
+        Ok(())
+    }
+
+    fn is_processed(&self, id: &str) -> Result<bool, StoreError> {
+        Ok(self.db.exists(&format!("receipt:{}", id)))
+    }
+
+    fn mark_processed(&mut self, id: &str) -> Result<(), StoreError> {
+        Ok(self
+            .db
+            .set(&format!("receipt:{}", id), &chrono::Utc::now().to_rfc3339())?)
+    }
+
+    fn flush(&mut self) -> Result<(), StoreError> {
+        Ok(self.db.dump()?)
+    }
+
+    fn schema_version(&self) -> Result<u32, StoreError> {
+        if !self.db.exists("schema_version") {
+            return Ok(1);
+        }
+        self.db
+            .get("schema_version")
+            .ok_or_else(|| StoreError::Value("can't read schema_version".to_string()))
+    }
+
+    fn set_schema_version(&mut self, version: u32) -> Result<(), StoreError> {
+        Ok(self.db.set("schema_version", &version)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::categories::{assign_category, get_top_category};
+    use crate::filters::FilterStep;
+    use std::fs::{create_dir_all, remove_file};
+    use std::path::PathBuf;
+
+    fn roundtrip(store: &mut dyn UserStore) {
+        let mut catmap = CatStats::new();
+        assign_category("milk", "Expenses:Dairy", &mut catmap);
+        assign_category("milk", "Expenses:Dairy", &mut catmap);
+        assign_category("milk", "Expenses:Food", &mut catmap);
+        store.save_catmap(&catmap).unwrap();
+        assert_eq!(store.load_catmap().unwrap(), catmap);
+        store.add_hits("milk", "Expenses:Food", 2).unwrap();
+        store.add_hits("bread", "Expenses:Food", 1).unwrap();
+        add_hits("milk", "Expenses:Food", 2, &mut catmap);
+        add_hits("bread", "Expenses:Food", 1, &mut catmap);
+        assert_eq!(store.load_catmap().unwrap(), catmap);
+
+        let tree: AccountTree = [
+            AccountInfo::new(AccountKind::Expense, "Expenses:Dairy"),
+            AccountInfo {
+                hidden: true,
+                ..AccountInfo::new(AccountKind::Bank, "Assets:Bank")
+            },
+        ]
+        .into_iter()
+        .collect();
+        let accounts = HashSet::from(["Expenses:Dairy".to_string()]);
+        store.save_accounts(&accounts, &tree).unwrap();
+        assert_eq!(store.load_accounts().unwrap(), (accounts, tree));
+
+        assert_eq!(store.setting("account").unwrap(), None);
+        store.set_setting("account", "Wallet").unwrap();
+        store.set_setting("account", "Card").unwrap();
+        assert_eq!(store.setting("account").unwrap().as_deref(), Some("Card"));
+        store.remove_setting("account").unwrap();
+        store.remove_setting("account").unwrap();
+        assert_eq!(store.setting("account").unwrap(), None);
+        store.set_setting("account", "Card").unwrap();
+
+        assert!(!store.is_processed("fn:1:2").unwrap());
+        store.mark_processed("fn:1:2").unwrap();
+        store.mark_processed("fn:1:2").unwrap();
+        assert!(store.is_processed("fn:1:2").unwrap());
+        store.flush().unwrap();
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        create_dir_all(crate::user::DEFAULT_DB_PATH).unwrap();
+        let path = PathBuf::from(format!("{}{}", crate::user::DEFAULT_DB_PATH, name));
+        let _ = remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_sqlite_store() {
+        let path = temp_path("store_test.sqlite");
+        roundtrip(open(&path, &FilterConfig::default()).unwrap().as_mut());
+        // A fresh file is created as SQLite
+        assert!(fs::read(&path).unwrap().starts_with(SQLITE_HEADER));
+        let store = open(&path, &FilterConfig::default()).unwrap();
+        assert!(store.is_processed("fn:1:2").unwrap());
+        assert_eq!(store.schema_version().unwrap(), SCHEMA_VERSION);
+        assert!(!backup_path(&path, 1).exists());
+        remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_pickle_store() {
+        let path = temp_path("store_test.db");
+        PickleDb::new(
+            &path,
+            PickleDbDumpPolicy::AutoDump,
+            SerializationMethod::Json,
+        )
+        .dump()
+        .unwrap();
+        let mut store = open(&path, &FilterConfig::default()).unwrap();
+        roundtrip(store.as_mut());
+        assert!(fs::read_to_string(&path).unwrap().contains("catmap"));
+        remove_file(&path).unwrap();
+        remove_file(backup_path(&path, 1)).unwrap();
+    }
+
+    #[test]
+    fn test_migrate_legacy() {
+        let path = temp_path("store_migrate.db");
+        let mut catmap = CatStats::new();
+        add_hits("МОЛОКО  2,5%", "Expenses:Dairy", 2, &mut catmap);
+        add_hits("молоко 2,5%", "Expenses:Dairy", 1, &mut catmap);
+        add_hits("молоко 2,5%", "Expenses:Food", 2, &mut catmap);
+        add_hits("1: *Молоко 2,5%", "Expenses:Dairy", 1, &mut catmap);
+        let mut db = PickleDb::new(
+            &path,
+            PickleDbDumpPolicy::AutoDump,
+            SerializationMethod::Json,
+        );
+        db.set("catmap", &catmap).unwrap();
+        db.set("accounts", &vec!["Expenses:Dairy".to_string()])
+            .unwrap();
+        drop(db);
+        let original = fs::read(&path).unwrap();
+
+        let store = open(&path, &FilterConfig::default()).unwrap();
+        assert_eq!(store.schema_version().unwrap(), SCHEMA_VERSION);
+        let catmap = store.load_catmap().unwrap();
+        assert_eq!(catmap.len(), 1);
+        let filter = FilterConfig::default()
+            .category_filter(None)
+            .unwrap()
+            .build();
+        let key = filter("2: *молоко 2,5%").into_owned();
+        assert_eq!(get_top_category(&key, &catmap), Some("Expenses:Dairy"));
+        assert_eq!(catmap.get(&key).unwrap()[0].hits(), 4);
+        let (_, tree) = store.load_accounts().unwrap();
+        assert_eq!(
+            tree.get("Expenses:Dairy").unwrap().kind,
+            AccountKind::Expense
+        );
+        drop(store);
+
+        let backup = backup_path(&path, 1);
+        assert_eq!(fs::read(&backup).unwrap(), original);
+        // Nothing to migrate the second time
+        remove_file(&backup).unwrap();
+        open(&path, &FilterConfig::default()).unwrap();
+        assert!(!backup.exists());
+        remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_migrate_with_filters() {
+        let path = temp_path("store_migrate_filters.db");
+        let mut catmap = CatStats::new();
+        add_hits("Молоко", "Expenses:Dairy", 1, &mut catmap);
+        add_hits("МОЛОКО УЛЬТРА", "Expenses:Dairy", 2, &mut catmap);
+        let mut db = PickleDb::new(
+            &path,
+            PickleDbDumpPolicy::AutoDump,
+            SerializationMethod::Json,
+        );
+        db.set("catmap", &catmap).unwrap();
+        drop(db);
+
+        let mut filters = FilterConfig::default();
+        filters.default.category = vec![FilterStep::Replace {
+            pattern: "(".to_string(),
+            with: String::new(),
+        }];
+        let e = open(&path, &filters).err().unwrap();
+        assert!(matches!(e, StoreError::Filters(_)));
+        assert!(!e.is_corrupt());
+
+        filters.default.category = vec![
+            FilterStep::Casefold,
+            FilterStep::Replace {
+                pattern: " ультра".to_string(),
+                with: String::new(),
+            },
+        ];
+        let store = open(&path, &filters).unwrap();
+        let catmap = store.load_catmap().unwrap();
+        assert_eq!(catmap.len(), 1);
+        assert_eq!(catmap.get("молоко").unwrap()[0].hits(), 3);
+        drop(store);
+        remove_file(&path).unwrap();
+        for version in 1..=2 {
+            let _ = remove_file(backup_path(&path, version));
+        }
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let path = temp_path("store_corrupt.db");
+        // Malformed values are caught by the migrations already
+        fs::write(&path, r#"[{"catmap":"5"},{}]"#).unwrap();
+        assert!(open(&path, &FilterConfig::default())
+            .err()
+            .unwrap()
+            .is_corrupt());
+        remove_file(backup_path(&path, 1)).unwrap();
+
+        fs::write(&path, "{not json").unwrap();
+        assert!(open(&path, &FilterConfig::default())
+            .err()
+            .unwrap()
+            .is_corrupt());
+
+        fs::write(&path, [SQLITE_HEADER, b"garbage"].concat()).unwrap();
+        assert!(open(&path, &FilterConfig::default())
+            .err()
+            .unwrap()
+            .is_corrupt());
+        remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_newer_version() {
+        let path = temp_path("store_newer.sqlite");
+        open(&path, &FilterConfig::default())
+            .unwrap()
+            .set_schema_version(SCHEMA_VERSION + 1)
+            .unwrap();
+        assert!(
+            matches!(open(&path, &FilterConfig::default()), Err(StoreError::Version(v)) if v == SCHEMA_VERSION + 1)
+        );
+        remove_file(&path).unwrap();
+    }
+}
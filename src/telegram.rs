@@ -1,34 +1,47 @@
 use crate::categories;
-use crate::convert::{auto_cat_items, convert};
-use qif_generator::account::{Account, AccountType};
+use crate::convert::{auto_cat_items, convert, read_file};
+use crate::filters::FilterConfig;
+use crate::import::read_account_file;
+use crate::knowledge::{Format, Knowledge, KnowledgeError, MergeMode};
+use crate::receipt::try_parse_purchase;
+use qif_generator::account::AccountType;
 
 #[cfg(feature = "monitoring")]
 use crate::monitoring;
-use crate::tgusermanager::user_manager;
-use std::collections::{HashMap, HashSet};
+use crate::tgkeyboard::{
+    category_keyboard, items_keyboard, names_digest, parse_category, parse_index, parse_page,
+    parse_parent, sorted_items, PagedKeyboard, CATEGORY_CALLBACK, CATEGORY_PAGE_CALLBACK,
+    EDIT_CALLBACK, GROUP_CALLBACK, ITEM_PAGE_CALLBACK,
+};
+use crate::tgstorage::{
+    clear_spool, create_spool, create_spooled, remove_spooled, sweep_spool, FileStorage, SPOOL_TTL,
+};
+use crate::tgusermanager::{user_manager, Households, SharedUser, UserUpdate};
+use crate::user::{LoadError, User, MEMO_SETTING};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::fmt;
 
 use crate::tgusermanager::TgManagerCommand;
 use derive_more::From;
+use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
-use std::str::FromStr;
+use std::path::Path;
 use std::sync::Arc;
 use teloxide::types::{
-    InlineKeyboardButton, InlineKeyboardButtonKind, InlineKeyboardMarkup, InputFile, MediaKind,
-    MessageKind, ReplyMarkup,
+    InlineKeyboardButton, InlineKeyboardButtonKind, InlineKeyboardMarkup, InputFile,
+    KeyboardButton, KeyboardMarkup, ReplyMarkup,
 };
 use teloxide::{
-    dispatching::dialogue::InMemStorage, net::Download, prelude::*, types::File as TgFile,
-    utils::command::BotCommands, DownloadError, RequestError,
+    net::Download, prelude::*, types::File as TgFile, utils::command::BotCommands, DownloadError,
+    RequestError,
 };
 use thiserror::Error;
-use tokio::fs::File;
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{mpsc, oneshot, OwnedMutexGuard};
 
 #[cfg(feature = "telegram")]
 #[tokio::main]
-pub async fn bot() {
-    run().await;
+pub async fn bot(filters: FilterConfig) {
+    run(filters).await;
 }
 
 /// Possible error while receiving a file
@@ -48,6 +61,7 @@ enum FileReceiveError {
 
 /// Possible error while receiving a file
 #[cfg(feature = "telegram")]
+#[allow(dead_code)]
 #[derive(Debug, Error, From)]
 enum FileConvertError {
     /// Telegram request error
@@ -58,15 +72,6 @@ enum FileConvertError {
     Io(#[source] std::io::Error),
 }
 
-/// Possible error while receiving a file
-#[cfg(feature = "telegram")]
-#[derive(Debug, Error, From)]
-enum UserManagerError {
-    /// Manager didn't respond
-    #[error("Couldn't request user: {0}")]
-    Request(String),
-}
-
 use tokio::sync::mpsc::Sender;
 
 struct ManagerHandle<T> {
@@ -99,6 +104,39 @@ enum Command {
 
     #[command(description = "List accounts")]
     Accounts,
+
+    #[command(
+        description = "Rename or merge category: /renamecategory <from> <to>",
+        parse_with = "split"
+    )]
+    RenameCategory { from: String, to: String },
+
+    #[command(description = "List learned categories missing from accounts")]
+    Stale,
+
+    #[command(description = "Explain the category choice: /why <item>")]
+    Why { item: String },
+
+    #[command(description = "Export learned categories as a file")]
+    ExportCats,
+
+    #[command(description = "Import learned categories from a file: /importcats [sum|replace]")]
+    ImportCats { mode: String },
+
+    #[command(description = "Invite another user to share your categories and accounts")]
+    Invite,
+
+    #[command(description = "Join the household by invite code: /join <code>")]
+    Join { code: String },
+
+    #[command(description = "Leave the household and return to own categories")]
+    Leave,
+
+    #[command(description = "Set the account purchases are paid from: /setaccount [name] [type]")]
+    SetAccount { account: String },
+
+    #[command(description = "Set the memo used for \"-\" answer: /setmemo <memo>")]
+    SetMemo { memo: String },
 }
 
 async fn command_handler(
@@ -108,6 +146,7 @@ async fn command_handler(
     msg: Message,
     cmd: Command,
     manager_handle: Arc<ManagerHandle<TgManagerCommand>>,
+    filters: Arc<FilterConfig>,
 ) -> HandlerResult {
     let tx = &manager_handle.tx;
 
@@ -117,12 +156,36 @@ async fn command_handler(
                 .await?
         }
         Command::Start => {
-            bot.send_message(msg.chat.id, "Starting".to_string())
-                .await?
+            // Create the database right away
+            let (response_tx, response_rx) = oneshot::channel();
+            tx.send(TgManagerCommand::Save {
+                user_id: msg.chat.id.0,
+                reply_to: response_tx,
+            })
+            .await?;
+            if let Err(e) = response_rx.await? {
+                log::error!("Can't create user {}: {}", msg.chat.id.0, e);
+                bot.send_message(msg.chat.id, e.to_string()).await?;
+                return Ok(());
+            }
+            bot.send_message(msg.chat.id, WELCOME).await?;
+            dialogue.update(State::StartAccounts).await?;
+            bot.send_message(
+                msg.chat.id,
+                "Upload your accounts: GnuCash CSV export, ledger, hledger or beancount \
+                 file. Send \"skip\" to add them later with /newaccount",
+            )
+            .await?
         }
         Command::Delete => {
-            bot.send_message(msg.chat.id, "Deleting".to_string())
-                .await?
+            dialogue.update(State::DeleteConfirm).await?;
+            bot.send_message(
+                msg.chat.id,
+                "This erases your categories, accounts and receipt history. \
+                 Receipts converted in a shared household stay in its history. \
+                 Send \"yes\" to confirm",
+            )
+            .await?
         }
 
         Command::Request => {
@@ -132,6 +195,7 @@ async fn command_handler(
         Command::Cancel => {
             log::info!("Reset requested");
             dialogue.update(State::Idle).await?;
+            clear_spool(msg.chat.id.0).await?;
             bot.send_message(msg.chat.id, "Dialogue state reset".to_string())
                 .await?
         }
@@ -145,20 +209,19 @@ async fn command_handler(
             } else {
                 let (response_tx, response_rx) = oneshot::channel();
 
-                tx.send(TgManagerCommand::Get {
+                tx.send(TgManagerCommand::Update {
                     user_id: msg.chat.id.0,
+                    update: UserUpdate::AddAccount(String::from(acc_to_add)),
                     reply_to: response_tx,
                 })
                 .await?;
 
-                if let Ok(mut user) = response_rx.await {
-                    user.new_account(String::from(acc_to_add));
-                    bot.send_message(msg.chat.id, "Account added".to_string())
-                        .await?
-                } else {
-                    log::error!("Request for unknown userid {}", msg.chat.id.0);
-                    bot.send_message(msg.chat.id, "Can't find the requested user".to_string())
-                        .await?
+                match response_rx.await? {
+                    Ok(_) => {
+                        bot.send_message(msg.chat.id, "Account added".to_string())
+                            .await?
+                    }
+                    Err(e) => bot.send_message(msg.chat.id, e.to_string()).await?,
                 }
             }
         }
@@ -171,50 +234,331 @@ async fn command_handler(
             })
             .await?;
 
-            if let Ok(user) = response_rx.await {
-                let list = |expense_set: &HashSet<String>| {
-                    let mut sorted_expenses: Vec<String> = expense_set
-                        .iter()
-                        .filter(|s| s.starts_with("Expenses:"))
-                        .map(|s| s.trim_start_matches("Expenses:").to_owned())
-                        .collect();
+            match user_reply(msg.chat.id.0, response_rx).await {
+                Ok(user) => {
+                    let list = |expense_set: &HashSet<String>| {
+                        let mut sorted_expenses: Vec<String> = expense_set
+                            .iter()
+                            .filter(|s| s.starts_with("Expenses:"))
+                            .map(|s| s.trim_start_matches("Expenses:").to_owned())
+                            .collect();
+
+                        sorted_expenses.sort();
+
+                        sorted_expenses
+                            .into_iter()
+                            .map(|s| s + "\n")
+                            .collect::<String>()
+                    };
+
+                    let sources = user
+                        .account_tree
+                        .sources()
+                        .filter(|a| a.is_postable())
+                        .map(|a| format!("{} ({})\n", a.name, a.kind))
+                        .collect::<String>();
+                    let text = if sources.is_empty() {
+                        format!("Expense accounts:\n\n{}", list(&user.accounts))
+                    } else {
+                        format!(
+                            "Expense accounts:\n\n{}\nPayment accounts:\n\n{}",
+                            list(&user.accounts),
+                            sources
+                        )
+                    };
+                    drop(user);
+
+                    bot.send_message(msg.chat.id, text).await?
+                }
+                Err(text) => bot.send_message(msg.chat.id, text).await?,
+            }
+        }
+        Command::RenameCategory { from, to } => {
+            let (response_tx, response_rx) = oneshot::channel();
+
+            tx.send(TgManagerCommand::Update {
+                user_id: msg.chat.id.0,
+                update: UserUpdate::RenameCategory {
+                    from: from.clone(),
+                    to: to.clone(),
+                },
+                reply_to: response_tx,
+            })
+            .await?;
+
+            let text = match response_rx.await? {
+                Err(e) => e.to_string(),
+                Ok(affected) if affected.is_empty() => {
+                    format!("No items found in category {}", from)
+                }
+                Ok(affected) => format!(
+                    "Moved to {}:\n\n{}",
+                    to,
+                    affected.into_iter().map(|s| s + "\n").collect::<String>()
+                ),
+            };
+            bot.send_message(msg.chat.id, text).await?
+        }
+        Command::Stale => {
+            let (response_tx, response_rx) = oneshot::channel();
+
+            tx.send(TgManagerCommand::Get {
+                user_id: msg.chat.id.0,
+                reply_to: response_tx,
+            })
+            .await?;
+
+            match user_reply(msg.chat.id.0, response_rx).await {
+                Ok(user) => {
+                    let stale = categories::stale_categories(&user.catmap, &user.accounts);
+                    let text = if stale.is_empty() {
+                        "All the learned categories are present in accounts".to_string()
+                    } else {
+                        format!(
+                            "Missing categories:\n\n{}\nUse /renamecategory <from> <to> to remap",
+                            stale
+                                .iter()
+                                .map(|(cat, items)| format!("{}: {}\n", cat, items.join(", ")))
+                                .collect::<String>()
+                        )
+                    };
+                    drop(user);
+                    bot.send_message(msg.chat.id, text).await?
+                }
+                Err(text) => bot.send_message(msg.chat.id, text).await?,
+            }
+        }
+        Command::Why { item } => {
+            let item = item.trim();
 
-                    sorted_expenses.sort();
+            if item.is_empty() {
+                log::warn!("/why executed without item name");
+                bot.send_message(msg.chat.id, "No item name provided".to_string())
+                    .await?
+            } else {
+                let (response_tx, response_rx) = oneshot::channel();
 
-                    sorted_expenses
-                        .into_iter()
-                        .map(|s| s + "\n")
-                        .collect::<String>()
+                tx.send(TgManagerCommand::Get {
+                    user_id: msg.chat.id.0,
+                    reply_to: response_tx,
+                })
+                .await?;
+
+                match user_reply(msg.chat.id.0, response_rx).await {
+                    Ok(user) => {
+                        let filter = filters.category_filter(None)?.build();
+                        let explanation =
+                            categories::explain(item, filter, &user.catmap, &user.accounts)
+                                .to_string();
+                        drop(user);
+                        bot.send_message(msg.chat.id, explanation).await?
+                    }
+                    Err(text) => bot.send_message(msg.chat.id, text).await?,
+                }
+            }
+        }
+        Command::ExportCats => {
+            let (response_tx, response_rx) = oneshot::channel();
+
+            tx.send(TgManagerCommand::Get {
+                user_id: msg.chat.id.0,
+                reply_to: response_tx,
+            })
+            .await?;
+
+            match user_reply(msg.chat.id.0, response_rx).await {
+                Ok(user) => {
+                    let data = user.export_knowledge().render(Format::Json)?;
+                    drop(user);
+                    let file = InputFile::memory(data.into_bytes()).file_name("categories.json");
+                    bot.send_document(msg.chat.id, file).await?
+                }
+                Err(text) => bot.send_message(msg.chat.id, text).await?,
+            }
+        }
+        Command::ImportCats { mode } => {
+            let mode = if mode.trim().is_empty() {
+                Ok(MergeMode::default())
+            } else {
+                mode.parse::<MergeMode>()
+            };
+            match mode {
+                Ok(mode) => {
+                    dialogue.update(State::ImportKnowledge { mode }).await?;
+                    bot.send_message(
+                        msg.chat.id,
+                        "Upload categories file in JSON or csv format".to_string(),
+                    )
+                    .await?
+                }
+                Err(e) => bot.send_message(msg.chat.id, e.to_string()).await?,
+            }
+        }
+        Command::SetAccount { account } => {
+            let account = account.trim();
+            if account.is_empty() {
+                let (response_tx, response_rx) = oneshot::channel();
+                tx.send(TgManagerCommand::Get {
+                    user_id: msg.chat.id.0,
+                    reply_to: response_tx,
+                })
+                .await?;
+
+                match user_reply(msg.chat.id.0, response_rx).await {
+                    Ok(user) => {
+                        let sources = user.source_accounts();
+                        drop(user);
+                        if sources.is_empty() {
+                            bot.send_message(
+                                msg.chat.id,
+                                "No payment accounts imported, use /setaccount <name> [type]",
+                            )
+                            .await?
+                        } else {
+                            bot.send_message(msg.chat.id, "Choose the account you pay from")
+                                .reply_markup(source_keyboard(&sources, SOURCE_CALLBACK))
+                                .await?
+                        }
+                    }
+                    Err(text) => bot.send_message(msg.chat.id, text).await?,
+                }
+            } else {
+                // The type may follow the name, which can contain spaces
+                let (name, account_type) = match account.rsplit_once(' ') {
+                    Some((name, t)) => match t.parse::<AccountType>() {
+                        Ok(t) => (name.trim(), Some(t)),
+                        Err(_) => (account, None),
+                    },
+                    None => (account, None),
                 };
+                let (response_tx, response_rx) = oneshot::channel();
+                tx.send(TgManagerCommand::Update {
+                    user_id: msg.chat.id.0,
+                    update: UserUpdate::PaymentAccount {
+                        name: name.to_string(),
+                        account_type,
+                    },
+                    reply_to: response_tx,
+                })
+                .await?;
 
-                bot.send_message(
-                    msg.chat.id,
-                    format!("Expense accounts:\n\n{}", list(&user.accounts)),
-                )
-                .await?
+                let text = match response_rx.await? {
+                    Ok(_) => format!("Purchases are paid from {}", name),
+                    Err(e) => e.to_string(),
+                };
+                bot.send_message(msg.chat.id, text).await?
+            }
+        }
+        Command::SetMemo { memo } => {
+            let memo = memo.trim();
+            if memo.is_empty() {
+                bot.send_message(msg.chat.id, "No memo provided".to_string())
+                    .await?
             } else {
-                log::error!("Request for unknown userid {}", msg.chat.id.0);
-                bot.send_message(msg.chat.id, "Can't find the requested user".to_string())
+                let (response_tx, response_rx) = oneshot::channel();
+                tx.send(TgManagerCommand::Update {
+                    user_id: msg.chat.id.0,
+                    update: UserUpdate::SetSetting {
+                        key: MEMO_SETTING.to_string(),
+                        value: memo.to_string(),
+                    },
+                    reply_to: response_tx,
+                })
+                .await?;
+
+                let text = match response_rx.await? {
+                    Ok(_) => "Default memo is set".to_string(),
+                    Err(e) => e.to_string(),
+                };
+                bot.send_message(msg.chat.id, text).await?
+            }
+        }
+        Command::Invite => {
+            let (response_tx, response_rx) = oneshot::channel();
+
+            tx.send(TgManagerCommand::Invite {
+                user_id: msg.chat.id.0,
+                reply_to: response_tx,
+            })
+            .await?;
+
+            let text = match response_rx.await? {
+                Ok(code) => format!(
+                    "Send this to the invited user, the code works once within a day:\n\n/join {}",
+                    code
+                ),
+                Err(e) => e.to_string(),
+            };
+            bot.send_message(msg.chat.id, text).await?
+        }
+        Command::Join { code } => {
+            let code = code.trim();
+
+            if code.is_empty() {
+                log::warn!("/join executed without invite code");
+                bot.send_message(msg.chat.id, "No invite code provided".to_string())
                     .await?
+            } else {
+                let (response_tx, response_rx) = oneshot::channel();
+
+                tx.send(TgManagerCommand::Join {
+                    user_id: msg.chat.id.0,
+                    code: code.to_string(),
+                    reply_to: response_tx,
+                })
+                .await?;
+
+                let text = match response_rx.await? {
+                    Ok(_) => {
+                        "Joined the household, categories and accounts are now shared".to_string()
+                    }
+                    Err(e) => e.to_string(),
+                };
+                bot.send_message(msg.chat.id, text).await?
             }
         }
+        Command::Leave => {
+            let (response_tx, response_rx) = oneshot::channel();
+
+            tx.send(TgManagerCommand::Leave {
+                user_id: msg.chat.id.0,
+                reply_to: response_tx,
+            })
+            .await?;
+
+            let text = match response_rx.await? {
+                Ok(()) => "Left the household, own categories are used again".to_string(),
+                Err(e) => e.to_string(),
+            };
+            bot.send_message(msg.chat.id, text).await?
+        }
     };
 
     Ok(())
 }
 
-async fn download_file(downloader: &Bot, file_id: &str) -> Result<String, FileReceiveError> {
+/// Download the file into the spool of `chat_id`, so the session can be
+/// resumed after restart
+async fn download_file(
+    downloader: &Bot,
+    chat_id: ChatId,
+    file_id: &str,
+) -> Result<String, FileReceiveError> {
     let TgFile { path, .. } = downloader.get_file(file_id).send().await?;
     log::info!("Attempt to download file");
-    let filepath = format!("/tmp/{}", file_id);
+    let filepath = create_spool(chat_id.0)?
+        .join(file_id)
+        .to_string_lossy()
+        .into_owned();
     log::info!("Path: {}", filepath);
-    let mut file = File::create(&filepath).await?;
+    let mut file = create_spooled(Path::new(&filepath)).await?;
     downloader.download_file(&path, &mut file).await?;
     Ok(filepath)
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub enum State {
+    #[default]
     Idle,
 
     NewJson {
@@ -222,29 +566,51 @@ pub enum State {
     },
 
     CategorySelect {
-        filename: String,
+        /// Receipts of the session
+        filenames: Vec<String>,
         item: String,
         items_left: Vec<String>,
         items_processed: HashMap<String, String>,
     },
 
     SubCategorySelect {
-        filename: String,
+        filenames: Vec<String>,
         item: String,
         category: String,
         items_left: Vec<String>,
         items_processed: HashMap<String, String>,
+        /// Search results the category buttons refer to by index
+        #[serde(default)]
+        results: Vec<String>,
     },
 
     Ready {
-        filename: String,
+        filenames: Vec<String>,
         item_categories: HashMap<String, String>,
+        /// Payment account chosen for this receipt
+        account: Option<String>,
     },
+
+    ImportKnowledge {
+        mode: MergeMode,
+    },
+
+    /// Onboarding waits for the accounts file
+    StartAccounts,
+
+    /// Onboarding waits for the default payment account
+    StartSource,
+
+    DeleteConfirm,
 }
 
-impl Default for State {
-    fn default() -> Self {
-        Self::Idle
+impl State {
+    /// Receipts are being categorized or converted, more can be added
+    fn is_session(&self) -> bool {
+        matches!(
+            self,
+            State::CategorySelect { .. } | State::SubCategorySelect { .. } | State::Ready { .. }
+        )
     }
 }
 
@@ -254,37 +620,78 @@ impl fmt::Display for State {
             State::Idle => write!(f, "Idle"),
             State::NewJson { filename } => write!(f, "NewJson {}", filename),
             State::CategorySelect {
-                filename,
+                filenames,
                 item,
                 items_left: _,
                 items_processed: _,
             } => {
-                write!(f, "Category: {}, {}", filename, item)
+                write!(f, "Category: {:?}, {}", filenames, item)
             }
             State::SubCategorySelect {
-                filename,
+                filenames,
                 item,
                 category,
                 items_left: _,
                 items_processed: _,
-            } => write!(f, "SubCategory: {}, {}, {}", filename, item, category),
+                results: _,
+            } => write!(f, "SubCategory: {:?}, {}, {}", filenames, item, category),
             State::Ready {
-                filename,
+                filenames,
                 item_categories,
+                account: _,
             } => write!(
                 f,
-                "Conversion is ready for file {} the following items: {:#?}",
-                filename, item_categories
+                "Conversion is ready for files {:?} the following items: {:#?}",
+                filenames, item_categories
             ),
+            State::ImportKnowledge { mode } => write!(f, "ImportKnowledge {:?}", mode),
+            State::StartAccounts => write!(f, "StartAccounts"),
+            State::StartSource => write!(f, "StartSource"),
+            State::DeleteConfirm => write!(f, "DeleteConfirm"),
         }
     }
 }
 
-type QIFDialogue = Dialogue<State, InMemStorage<State>>;
-type HandlerResult = Result<(), Box<dyn std::error::Error + Send + Sync>>;
+/// Lock the user from the manager reply, failures are turned into the text
+/// for the chat
+async fn user_reply(
+    user_id: i64,
+    reply: oneshot::Receiver<Result<SharedUser, LoadError>>,
+) -> Result<OwnedMutexGuard<User>, String> {
+    match reply.await {
+        Ok(Ok(user)) => Ok(user.lock_owned().await),
+        Ok(Err(e)) => {
+            log::error!("Can't load user {}: {}", user_id, e);
+            Err(match e {
+                LoadError::Corrupt { .. } => "Your saved data can't be read, it's put aside \
+                    for recovery. Repeat the request to start over"
+                    .to_string(),
+                _ => "Can't load your data, try again later".to_string(),
+            })
+        }
+        Err(_) => {
+            log::error!("Request for unknown userid {}", user_id);
+            Err("Can't find the requested user".to_string())
+        }
+    }
+}
 
-async fn handle_idle(bot: Bot, dialogue: QIFDialogue, msg: Message) -> HandlerResult {
+type QIFDialogue = Dialogue<State, FileStorage<State>>;
+type HandlerError = Box<dyn std::error::Error + Send + Sync>;
+type HandlerResult = Result<(), HandlerError>;
+
+async fn handle_idle(
+    bot: Bot,
+    dialogue: QIFDialogue,
+    msg: Message,
+    manager_handle: Arc<ManagerHandle<TgManagerCommand>>,
+    filters: Arc<FilterConfig>,
+) -> HandlerResult {
     log::debug!("Idle state");
+    // The receipt may come right away, e.g. after /cancel
+    if msg.document().is_some() {
+        return handle_json(bot, dialogue, msg, String::new(), manager_handle, filters).await;
+    }
     bot.send_message(msg.chat.id, "Upload your file").await?;
     dialogue
         .update(State::NewJson {
@@ -316,39 +723,423 @@ fn format_categories(catitems: &HashMap<String, String>) -> String {
 }
  */
 
-fn create_categories_keyboard(catitems: &HashMap<String, String>) -> InlineKeyboardMarkup {
-    let mut keyboard = InlineKeyboardMarkup::default(); // Use default to initialize
-
-    for (index, (item, category)) in catitems.iter().enumerate() {
-        let parts: Vec<&str> = category.split(':').collect();
-        let shortened_category = if parts.len() > 1 {
-            parts[..parts.len() - 1]
-                .iter()
-                .map(|&part| {
-                    part.chars()
-                        .next()
-                        .unwrap_or_default()
-                        .to_uppercase()
-                        .collect::<String>()
-                })
-                .chain(std::iter::once(parts.last().unwrap().to_string()))
-                .collect::<Vec<String>>()
-                .join(":")
-        } else {
-            category.clone()
-        };
+async fn handle_import_knowledge(
+    bot: Bot,
+    dialogue: QIFDialogue,
+    msg: Message,
+    mode: MergeMode, // Available from `State::ImportKnowledge`.
+    manager_handle: Arc<ManagerHandle<TgManagerCommand>>,
+) -> HandlerResult {
+    let doc = match msg.document() {
+        Some(doc) => doc,
+        None => {
+            bot.send_message(msg.chat.id, "Upload categories file or /cancel")
+                .await?;
+            return Ok(());
+        }
+    };
+    if let Err(e) = check_data_upload(
+        doc.file_name.as_deref(),
+        doc.file.size,
+        &KNOWLEDGE_EXTENSIONS,
+    ) {
+        log::warn!("Rejected categories upload {:?}: {}", doc.file_name, e);
+        bot.send_message(msg.chat.id, format!("{}. Try another file or /cancel", e))
+            .await?;
+        return Ok(());
+    }
+    let format = match &doc.file_name {
+        Some(name) => Format::from_path(std::path::Path::new(name)),
+        None => Format::Json,
+    };
+
+    let path = match download_file(&bot, msg.chat.id, &doc.file.id).await {
+        Ok(path) => path,
+        Err(e) => {
+            log::error!("Can't download {}: {}", doc.file.id, e);
+            bot.send_message(msg.chat.id, "Can't download the file, try again")
+                .await?;
+            return Ok(());
+        }
+    };
+    let knowledge = tokio::fs::read_to_string(&path)
+        .await
+        .map_err(KnowledgeError::from)
+        .and_then(|data| Knowledge::parse(&data, format));
+    remove_spooled(&path).await?;
+
+    let knowledge = match knowledge {
+        Ok(knowledge) => knowledge,
+        Err(e) => {
+            log::warn!("Can't import categories: {}", e);
+            bot.send_message(msg.chat.id, e.to_string()).await?;
+            return Ok(());
+        }
+    };
+
+    let tx = &manager_handle.tx;
+    let (response_tx, response_rx) = oneshot::channel();
+    let merged = knowledge.catmap.len();
+    tx.send(TgManagerCommand::Update {
+        user_id: msg.chat.id.0,
+        update: UserUpdate::ImportKnowledge { knowledge, mode },
+        reply_to: response_tx,
+    })
+    .await?;
+
+    let text = match response_rx.await? {
+        Ok(_) => format!("Imported {} items", merged),
+        Err(e) => {
+            log::error!("Can't import categories for {}: {}", msg.chat.id.0, e);
+            e.to_string()
+        }
+    };
+    bot.send_message(msg.chat.id, text).await?;
+    dialogue.update(State::Idle).await?;
+    Ok(())
+}
+
+/// Workflow explanation for the new users
+const WELCOME: &str = "Send me receipt JSON files exported from the FNS \
+    receipt checking app and I'll turn them into QIF for your accounting \
+    program. I'll ask for the category of each new item and remember the \
+    choice for the next receipts.";
+
+/// Whether the text is the answer to skip the onboarding step
+fn is_skip(msg: &Message) -> bool {
+    msg.text()
+        .is_some_and(|text| text.trim().eq_ignore_ascii_case("skip"))
+}
 
-        let button_text = format!("{}: {}", item, shortened_category);
-        // Using only the index as callback data to avoid exceeding the maximum length
-        let callback_data = format!("edit_{}", index);
-        log::info!("Text: '{}'  Data: '{}'", button_text, callback_data);
+async fn handle_start_accounts(
+    bot: Bot,
+    dialogue: QIFDialogue,
+    msg: Message,
+    manager_handle: Arc<ManagerHandle<TgManagerCommand>>,
+) -> HandlerResult {
+    let doc = match msg.document() {
+        Some(doc) => doc,
+        None if is_skip(&msg) => return ask_source(bot, dialogue, msg, manager_handle).await,
+        None => {
+            bot.send_message(msg.chat.id, "Upload the accounts file or send \"skip\"")
+                .await?;
+            return Ok(());
+        }
+    };
 
-        let button = InlineKeyboardButton::callback(button_text, callback_data);
-        keyboard = keyboard.append_row(vec![button]);
+    if let Err(e) = check_data_upload(doc.file_name.as_deref(), doc.file.size, &ACCOUNT_EXTENSIONS)
+    {
+        log::warn!("Rejected accounts upload {:?}: {}", doc.file_name, e);
+        bot.send_message(
+            msg.chat.id,
+            format!("{}. Try another file or send \"skip\"", e),
+        )
+        .await?;
+        return Ok(());
+    }
+
+    // The reader is chosen by the file extension
+    let path = match download_file(&bot, msg.chat.id, &doc.file.id).await {
+        Ok(path) => path,
+        Err(e) => {
+            log::error!("Can't download {}: {}", doc.file.id, e);
+            bot.send_message(msg.chat.id, "Can't download the file, try again")
+                .await?;
+            return Ok(());
+        }
+    };
+    let extension = doc
+        .file_name
+        .as_deref()
+        .and_then(|name| Path::new(name).extension())
+        .and_then(|ext| ext.to_str())
+        .unwrap_or("csv");
+    let named = format!("{}.{}", path, extension);
+    tokio::fs::rename(&path, &named).await?;
+    let tree = read_account_file(Path::new(&named)).map_err(|e| e.to_string());
+    remove_spooled(&named).await?;
+
+    let tree = match tree {
+        Ok(tree) => tree,
+        Err(e) => {
+            log::warn!("Can't import accounts: {}", e);
+            bot.send_message(
+                msg.chat.id,
+                format!(
+                    "Can't read the accounts: {}. Try another file or send \"skip\"",
+                    e
+                ),
+            )
+            .await?;
+            return Ok(());
+        }
+    };
+
+    let tx = &manager_handle.tx;
+    let (response_tx, response_rx) = oneshot::channel();
+    let categories = tree.categories().filter(|a| a.is_postable()).count();
+    tx.send(TgManagerCommand::Update {
+        user_id: msg.chat.id.0,
+        update: UserUpdate::AccountTree(tree),
+        reply_to: response_tx,
+    })
+    .await?;
+
+    if let Err(e) = response_rx.await? {
+        log::error!("Can't import accounts for {}: {}", msg.chat.id.0, e);
+        bot.send_message(msg.chat.id, e.to_string()).await?;
+        return Ok(());
+    }
+    bot.send_message(msg.chat.id, format!("Imported {} categories", categories))
+        .await?;
+    ask_source(bot, dialogue, msg, manager_handle).await
+}
+
+/// Offer the postable payment accounts of the user
+async fn ask_source(
+    bot: Bot,
+    dialogue: QIFDialogue,
+    msg: Message,
+    manager_handle: Arc<ManagerHandle<TgManagerCommand>>,
+) -> HandlerResult {
+    let tx = &manager_handle.tx;
+    let (response_tx, response_rx) = oneshot::channel();
+    tx.send(TgManagerCommand::Get {
+        user_id: msg.chat.id.0,
+        reply_to: response_tx,
+    })
+    .await?;
+
+    let sources = match user_reply(msg.chat.id.0, response_rx).await {
+        Ok(user) => user.source_accounts(),
+        Err(text) => {
+            bot.send_message(msg.chat.id, text).await?;
+            return Ok(());
+        }
+    };
+
+    dialogue.update(State::StartSource).await?;
+    let request = bot.send_message(
+        msg.chat.id,
+        "Which account do you pay from? Send its name, e.g. Assets:Cash, or \"skip\"",
+    );
+    if sources.is_empty() {
+        request.await?;
+    } else {
+        let keyboard: Vec<Vec<KeyboardButton>> = sources
+            .into_iter()
+            .map(|name| vec![KeyboardButton::new(name)])
+            .collect();
+        request
+            .reply_markup(
+                KeyboardMarkup::new(keyboard)
+                    .one_time_keyboard(true)
+                    .resize_keyboard(true),
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+async fn handle_start_source(
+    bot: Bot,
+    dialogue: QIFDialogue,
+    msg: Message,
+    manager_handle: Arc<ManagerHandle<TgManagerCommand>>,
+) -> HandlerResult {
+    let account = match msg.text().map(str::trim) {
+        Some(account) if !account.is_empty() => account,
+        _ => {
+            bot.send_message(msg.chat.id, "Send the account name or \"skip\"")
+                .await?;
+            return Ok(());
+        }
+    };
+
+    if !is_skip(&msg) {
+        let tx = &manager_handle.tx;
+        let (response_tx, response_rx) = oneshot::channel();
+        tx.send(TgManagerCommand::Update {
+            user_id: msg.chat.id.0,
+            update: UserUpdate::PaymentAccount {
+                name: account.to_string(),
+                account_type: None,
+            },
+            reply_to: response_tx,
+        })
+        .await?;
+        if let Err(e) = response_rx.await? {
+            bot.send_message(msg.chat.id, e.to_string()).await?;
+            return Ok(());
+        }
+    }
+
+    dialogue
+        .update(State::NewJson {
+            filename: String::new(),
+        })
+        .await?;
+    bot.send_message(msg.chat.id, "All set, upload your first receipt")
+        .reply_markup(ReplyMarkup::kb_remove())
+        .await?;
+    Ok(())
+}
+
+async fn handle_delete_confirm(
+    bot: Bot,
+    dialogue: QIFDialogue,
+    msg: Message,
+    manager_handle: Arc<ManagerHandle<TgManagerCommand>>,
+) -> HandlerResult {
+    let confirmed = msg
+        .text()
+        .is_some_and(|text| text.trim().eq_ignore_ascii_case("yes"));
+    if !confirmed {
+        dialogue.update(State::Idle).await?;
+        bot.send_message(msg.chat.id, "Deletion cancelled").await?;
+        return Ok(());
+    }
+
+    let tx = &manager_handle.tx;
+    let (response_tx, response_rx) = oneshot::channel();
+    tx.send(TgManagerCommand::Delete {
+        user_id: msg.chat.id.0,
+        reply_to: response_tx,
+    })
+    .await?;
+
+    match response_rx.await? {
+        Ok(()) => {
+            log::info!("User {} deleted", msg.chat.id.0);
+            dialogue.exit().await?;
+            clear_spool(msg.chat.id.0).await?;
+            bot.send_message(
+                msg.chat.id,
+                "All your data is deleted. Send /start to begin again",
+            )
+            .await?;
+        }
+        Err(e) => {
+            log::error!("Can't delete user {}: {}", msg.chat.id.0, e);
+            dialogue.update(State::Idle).await?;
+            bot.send_message(msg.chat.id, e.to_string()).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Deepest account which is a parent of all the `names`
+fn common_parent(names: &[&String]) -> String {
+    let mut parts: Vec<&str> = match names.first() {
+        Some(name) => name.split(':').collect(),
+        None => return String::new(),
+    };
+    for name in names {
+        let other: Vec<&str> = name.split(':').collect();
+        let common = parts
+            .iter()
+            .zip(other.iter())
+            .take_while(|(a, b)| a == b)
+            .count();
+        parts.truncate(common.min(other.len() - 1));
+    }
+    parts.join(":")
+}
+
+/// Callback data prefix for the default payment account buttons
+const SOURCE_CALLBACK: &str = "source:";
+
+/// Callback data prefix for the payment account of the current receipt
+const PAY_CALLBACK: &str = "pay:";
+
+/// Reply to a category button made for other search results
+const CATEGORY_KEYBOARD_EXPIRED: &str = "The keyboard has expired, enter the category again";
+
+/// Keyboard with payment accounts, the callback data is `prefix`, the
+/// digest of `sources` and the index in them, as the names may be too long
+/// for it
+fn source_keyboard(sources: &[String], prefix: &str) -> InlineKeyboardMarkup {
+    let digest = names_digest(sources);
+    let mut keyboard = InlineKeyboardMarkup::default();
+    for (index, source) in sources.iter().enumerate() {
+        keyboard = keyboard.append_row(vec![InlineKeyboardButton::new(
+            source,
+            InlineKeyboardButtonKind::CallbackData(format!("{}{}:{}", prefix, digest, index)),
+        )]);
     }
     keyboard
 }
 
+/// Payment account the `source_keyboard` button `index` stands for, None if
+/// the accounts have changed since the keyboard was made
+async fn chosen_source(
+    manager_handle: &ManagerHandle<TgManagerCommand>,
+    chat: ChatId,
+    index: &str,
+) -> Result<Option<String>, HandlerError> {
+    let (response_tx, response_rx) = oneshot::channel();
+    manager_handle
+        .tx
+        .send(TgManagerCommand::Get {
+            user_id: chat.0,
+            reply_to: response_tx,
+        })
+        .await?;
+    Ok(match user_reply(chat.0, response_rx).await {
+        Ok(user) => {
+            let sources = user.source_accounts();
+            parse_index(&sources, index).map(|index| sources[index].clone())
+        }
+        Err(_) => None,
+    })
+}
+
+/// Search results the category keyboard of the dialogue was built from,
+/// the buttons refer to them by index
+async fn with_category_results<T, F>(
+    dialogue: &QIFDialogue,
+    f: F,
+) -> Result<Option<T>, HandlerError>
+where
+    F: FnOnce(&[&String]) -> Option<T>,
+{
+    Ok(match dialogue.get().await? {
+        Some(State::SubCategorySelect { results, .. }) => f(&results.iter().collect::<Vec<_>>()),
+        _ => None,
+    })
+}
+
+/// Memo request with the payment account choice for the receipt
+fn memo_prompt(user: &User, uid: i64) -> (String, InlineKeyboardMarkup) {
+    let settings = user.settings(uid);
+    let account = match &settings.account {
+        Some(account) => format!("paid from {}", account),
+        None => "choose the account it's paid from".to_string(),
+    };
+    (
+        format!(
+            "Enter the memo line or \"-\" for \"{}\", {}",
+            settings.memo, account
+        ),
+        source_keyboard(&user.source_accounts(), PAY_CALLBACK),
+    )
+}
+
+/// Categories matching the search `text`, sorted
+fn search_categories<'a>(accounts: &'a HashSet<String>, text: &str) -> Vec<&'a String> {
+    let text = text.to_lowercase();
+    let mut found = if text.contains(':') {
+        filter_categories(accounts.iter(), &text)
+    } else {
+        accounts
+            .iter()
+            .filter(|&e| e.starts_with("Expenses:") && e.to_lowercase().contains(&text))
+            .collect::<Vec<_>>()
+    };
+    found.sort_unstable();
+    found
+}
+
 /// Fuzzy matcher for "A:B" to "ACategory:BSubCategory"
 fn filter_categories<'a, I>(categories: I, input: &str) -> Vec<&'a String>
 where
@@ -373,107 +1164,409 @@ where
         .collect()
 }
 
-async fn handle_json(
+/// Biggest accepted receipt file, the exports are a few kilobytes
+const MAX_UPLOAD_SIZE: u32 = 1024 * 1024;
+
+/// Biggest accepted accounts or categories file
+const MAX_DATA_SIZE: u32 = 5 * 1024 * 1024;
+
+/// Categories files `Knowledge` can read
+const KNOWLEDGE_EXTENSIONS: [&str; 2] = ["json", "csv"];
+
+/// Accounts files `read_account_file` can read
+const ACCOUNT_EXTENSIONS: [&str; 7] = [
+    "csv",
+    "journal",
+    "ledger",
+    "hledger",
+    "dat",
+    "beancount",
+    "bean",
+];
+
+/// Why the uploaded document is not accepted
+#[derive(Debug, Error, PartialEq)]
+enum UploadError {
+    #[error("The file is too large for a receipt, the limit is {} KiB", MAX_UPLOAD_SIZE / 1024)]
+    TooLarge,
+    #[error("Only JSON receipt exports are supported")]
+    NotJson,
+    #[error("The file is too large, the limit is {} KiB", MAX_DATA_SIZE / 1024)]
+    DataTooLarge,
+    #[error("Unsupported file type, expected one of: {0}")]
+    Extension(String),
+}
+
+/// Check the document before downloading it
+fn check_upload(name: Option<&str>, mime: Option<&str>, size: u32) -> Result<(), UploadError> {
+    if size > MAX_UPLOAD_SIZE {
+        return Err(UploadError::TooLarge);
+    }
+    let json_name = name
+        .and_then(|n| Path::new(n).extension())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("json"));
+    // Clients report JSON files differently, the name is trusted first
+    match mime {
+        _ if json_name => Ok(()),
+        Some("application/json") | Some("text/json") => Ok(()),
+        _ => Err(UploadError::NotJson),
+    }
+}
+
+/// Check the accounts or categories document before downloading it, the
+/// reader is chosen by its extension
+fn check_data_upload(
+    name: Option<&str>,
+    size: u32,
+    extensions: &[&str],
+) -> Result<(), UploadError> {
+    if size > MAX_DATA_SIZE {
+        return Err(UploadError::DataTooLarge);
+    }
+    let known = name
+        .and_then(|n| Path::new(n).extension())
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| extensions.iter().any(|e| ext.eq_ignore_ascii_case(e)));
+    if known {
+        Ok(())
+    } else {
+        Err(UploadError::Extension(extensions.join(", ")))
+    }
+}
+
+/// Guidance for a message which is not a receipt file
+fn not_a_file_reply(msg: &Message) -> &'static str {
+    if msg.sticker().is_some() {
+        "Nice sticker, but I need a receipt JSON file"
+    } else if msg.photo().is_some() {
+        "Receipt photos are not supported, send the JSON exported from the app"
+    } else {
+        "Upload the receipt JSON exported from the app, or /help for commands"
+    }
+}
+
+/// Validate, download and sniff the receipt document. None means the
+/// message was rejected and the user is told why.
+async fn receive_receipt(bot: &Bot, msg: &Message) -> Result<Option<String>, HandlerError> {
+    let doc = match msg.document() {
+        Some(doc) => doc,
+        None => {
+            bot.send_message(msg.chat.id, not_a_file_reply(msg)).await?;
+            return Ok(None);
+        }
+    };
+    if let Err(e) = check_upload(
+        doc.file_name.as_deref(),
+        doc.mime_type.as_ref().map(|m| m.essence_str()),
+        doc.file.size,
+    ) {
+        log::warn!("Rejected upload {:?}: {}", doc.file_name, e);
+        bot.send_message(msg.chat.id, e.to_string()).await?;
+        return Ok(None);
+    }
+
+    log::info!("File {} received", doc.file.id);
+    let newfile = match download_file(bot, msg.chat.id, &doc.file.id).await {
+        Ok(newfile) => newfile,
+        Err(e) => {
+            log::error!("Can't download {}: {}", doc.file.id, e);
+            bot.send_message(msg.chat.id, "Can't download the file, try again")
+                .await?;
+            return Ok(None);
+        }
+    };
+
+    let data = tokio::fs::read(&newfile).await?;
+    if let Err(e) = try_parse_purchase(&String::from_utf8_lossy(&data)) {
+        log::warn!("Malformed receipt {}: {}", newfile, e);
+        remove_spooled(&newfile).await?;
+        bot.send_message(
+            msg.chat.id,
+            format!(
+                "Can't read the receipt: {}. Send the JSON exported from the app",
+                e
+            ),
+        )
+        .await?;
+        return Ok(None);
+    }
+    log::info!("Active user: {:} File received: {:} ", msg.chat.id, newfile);
+    Ok(Some(newfile))
+}
+
+/// Items of the receipt with known categories and the unknown ones
+type Categorized = (HashMap<String, String>, Vec<String>);
+
+/// Categorize the known items of the spooled receipt, the memo prompt is
+/// returned for further replies, so that the user is not kept locked
+async fn categorize_receipt(
+    bot: &Bot,
+    msg: &Message,
+    manager_handle: &ManagerHandle<TgManagerCommand>,
+    filters: &FilterConfig,
+    newfile: &str,
+) -> Result<Option<(Categorized, (String, InlineKeyboardMarkup))>, HandlerError> {
+    let tx = &manager_handle.tx;
+    let (response_tx, response_rx) = oneshot::channel();
+
+    tx.send(TgManagerCommand::Get {
+        user_id: msg.chat.id.0,
+        reply_to: response_tx,
+    })
+    .await?;
+
+    let user = match user_reply(msg.chat.id.0, response_rx).await {
+        Ok(user) => user,
+        Err(text) => {
+            bot.send_message(msg.chat.id, text).await?;
+            return Ok(None);
+        }
+    };
+
+    let purchase = read_file(newfile);
+    let store = purchase.store().map(String::from);
+    let key_filter = filters.category_filter(store.as_deref())?.build();
+    let processed = user.is_processed(purchase.id());
+    let (cat, uncat) = auto_cat_items(newfile, &user, key_filter);
+    let prompt = memo_prompt(&user, msg.chat.id.0);
+    drop(user);
+
+    if processed {
+        bot.send_message(
+            msg.chat.id,
+            "This receipt was converted before, use /cancel to skip it".to_string(),
+        )
+        .await?;
+    }
+    log::debug!("Categorized item list: {:?}", cat);
+    log::debug!("Non-categorized item list: {:?}", uncat);
+    Ok(Some(((cat, uncat), prompt)))
+}
+
+/// Add the items of another receipt to the session, the items already
+/// categorized or waiting for a category are not asked again
+fn merge_items(
+    processed: &mut HashMap<String, String>,
+    left: &mut Vec<String>,
+    current: Option<&str>,
+    (cat, uncat): Categorized,
+) {
+    for (item, category) in cat {
+        processed.entry(item).or_insert(category);
+    }
+    for item in uncat {
+        if !processed.contains_key(&item) && current != Some(item.as_str()) && !left.contains(&item)
+        {
+            left.push(item);
+        }
+    }
+}
+
+async fn handle_json(
+    bot: Bot,
+    dialogue: QIFDialogue,
+    msg: Message,
+    filename: String, // Available from `State::Idle`.
+    manager_handle: Arc<ManagerHandle<TgManagerCommand>>,
+    filters: Arc<FilterConfig>,
+) -> HandlerResult {
+    log::debug!("JSON state");
+    log::info!("File {}", &filename);
+    #[cfg(feature = "monitoring")]
+    monitoring::INCOMING_REQUESTS.inc();
+
+    let newfile = match receive_receipt(&bot, &msg).await? {
+        Some(newfile) => newfile,
+        None => return Ok(()),
+    };
+    let ((cat, mut uncat), (text, keyboard)) =
+        match categorize_receipt(&bot, &msg, &manager_handle, &filters, &newfile).await? {
+            Some(categorized) => categorized,
+            None => return Ok(()),
+        };
+
+    if uncat.is_empty() {
+        log::info!("Automatically categorized");
+        bot.send_message(
+            msg.chat.id,
+            "Items are categorized and categories are updated".to_string(),
+        )
+        .reply_markup(items_keyboard(&cat, 0))
+        .await?;
+
+        bot.send_message(
+            msg.chat.id,
+            "All the items were categorized automatically".to_string(),
+        )
+        .await?;
+        bot.send_message(msg.chat.id, text)
+            .reply_markup(keyboard)
+            .await?;
+        dialogue
+            .update(State::Ready {
+                filenames: vec![newfile],
+                item_categories: cat,
+                account: None,
+            })
+            .await?;
+    } else if let Some(item) = uncat.pop() {
+        log::info!("No category for {}", &item);
+        bot.send_message(
+            msg.chat.id,
+            format!("Input category to search for {}", item),
+        )
+        .await?;
+        dialogue
+            .update(State::CategorySelect {
+                filenames: vec![newfile],
+                item,
+                items_left: uncat,
+                items_processed: cat,
+            })
+            .await?;
+    } else {
+        log::error!("Can't pop from non-empty list");
+    }
+    Ok(())
+}
+
+/// Another receipt sent during the session, e.g. a part of media group, is
+/// added to it
+async fn handle_batch_json(
     bot: Bot,
     dialogue: QIFDialogue,
     msg: Message,
-    filename: String, // Available from `State::Idle`.
+    state: State,
     manager_handle: Arc<ManagerHandle<TgManagerCommand>>,
+    filters: Arc<FilterConfig>,
 ) -> HandlerResult {
-    log::debug!("JSON state");
-    log::info!("File {}", &filename);
+    log::debug!("Batch JSON in state {}", state);
     #[cfg(feature = "monitoring")]
     monitoring::INCOMING_REQUESTS.inc();
 
-    let mut is_file = false;
-    let mut file_id: String = "".to_string();
-    {
-        if let MessageKind::Common(msg) = &msg.kind {
-            log::info!("It's message");
-            if let MediaKind::Document(doc) = &msg.media_kind {
-                is_file = true;
-                file_id = String::from_str(&doc.document.file.id).unwrap_or("".to_string());
-                log::info!("It's file with id {:}", file_id);
-            }
-        }
-    }
-
-    if is_file {
-        log::info!("File {} received", file_id);
-    }
-
-    if let Ok(newfile) = download_file(&bot, &file_id).await {
-        log::info!("Active user: {:} File received: {:} ", msg.chat.id, newfile);
-        let tx = &manager_handle.tx;
-        let (response_tx, response_rx) = oneshot::channel();
-
-        tx.send(TgManagerCommand::Get {
-            user_id: msg.chat.id.0,
-            reply_to: response_tx,
-        })
-        .await?;
-
-        let user = response_rx.await.map_err(|_| {
-            log::warn!("No response for TgUserManager");
-            Box::new(UserManagerError::Request(
-                "No response for TgUserManager".to_string(),
-            ))
-        })?;
-
-        let (cat, mut uncat) = auto_cat_items(&newfile, &user);
-
-        log::debug!("Categorized item list: {:?}", cat);
-        log::debug!("Non-categorized item list: {:?}", uncat);
-
-        if uncat.is_empty() {
-            log::info!("Automatically categorized");
-            bot.send_message(
-                msg.chat.id,
-                "Items are categorized and categories are updated".to_string(),
-            )
-            .reply_markup(create_categories_keyboard(&cat))
-            .await?;
+    let newfile = match receive_receipt(&bot, &msg).await? {
+        Some(newfile) => newfile,
+        None => return Ok(()),
+    };
+    let categorized =
+        match categorize_receipt(&bot, &msg, &manager_handle, &filters, &newfile).await? {
+            Some((categorized, _)) => categorized,
+            None => return Ok(()),
+        };
 
+    match state {
+        State::CategorySelect {
+            mut filenames,
+            item,
+            mut items_left,
+            mut items_processed,
+        } => {
+            filenames.push(newfile);
+            merge_items(
+                &mut items_processed,
+                &mut items_left,
+                Some(&item),
+                categorized,
+            );
             bot.send_message(
                 msg.chat.id,
-                "All the items were categorized automatically\nEnter the memo line".to_string(),
+                format!(
+                    "Receipt added, {} more items to categorize after {}",
+                    items_left.len(),
+                    item
+                ),
             )
             .await?;
             dialogue
-                .update(State::Ready {
-                    filename: newfile,
-                    item_categories: cat,
+                .update(State::CategorySelect {
+                    filenames,
+                    item,
+                    items_left,
+                    items_processed,
                 })
                 .await?;
-        } else if let Some(item) = uncat.pop() {
-            log::info!("No category for {}", &item);
+        }
+        State::SubCategorySelect {
+            mut filenames,
+            item,
+            category,
+            mut items_left,
+            mut items_processed,
+            results,
+        } => {
+            filenames.push(newfile);
+            merge_items(
+                &mut items_processed,
+                &mut items_left,
+                Some(&item),
+                categorized,
+            );
             bot.send_message(
                 msg.chat.id,
-                format!("Input category to search for {}", item),
+                format!(
+                    "Receipt added, {} more items to categorize after {}",
+                    items_left.len(),
+                    item
+                ),
             )
             .await?;
             dialogue
-                .update(State::CategorySelect {
-                    filename: newfile,
+                .update(State::SubCategorySelect {
+                    filenames,
                     item,
-                    items_left: uncat,
-                    items_processed: cat,
+                    category,
+                    items_left,
+                    items_processed,
+                    results,
                 })
                 .await?;
-        } else {
-            log::error!("Can't pop from non-empty list");
         }
-    } else {
-        log::warn!("Malformed json or categorization problem");
-        bot.send_message(msg.chat.id, "Can't parse the provided file".to_string())
-            .await?;
-        dialogue
-            .update(State::NewJson {
-                filename: String::new(),
-            })
-            .await?;
+        State::Ready {
+            mut filenames,
+            mut item_categories,
+            account,
+        } => {
+            filenames.push(newfile);
+            let mut items_left = vec![];
+            merge_items(&mut item_categories, &mut items_left, None, categorized);
+            if let Some(item) = items_left.pop() {
+                bot.send_message(
+                    msg.chat.id,
+                    format!("Receipt added, input category to search for {}", item),
+                )
+                .await?;
+                dialogue
+                    .update(State::CategorySelect {
+                        filenames,
+                        item,
+                        items_left,
+                        items_processed: item_categories,
+                    })
+                    .await?;
+            } else {
+                bot.send_message(
+                    msg.chat.id,
+                    format!(
+                        "Receipt added, all the items are categorized. {} receipts \
+                         wait for the memo line",
+                        filenames.len()
+                    ),
+                )
+                .await?;
+                dialogue
+                    .update(State::Ready {
+                        filenames,
+                        item_categories,
+                        account,
+                    })
+                    .await?;
+            }
+        }
+        other => {
+            log::error!("Receipt can't be added in state {}", other);
+            remove_spooled(&newfile).await?;
+        }
     }
-
     Ok(())
 }
 
@@ -482,8 +1575,8 @@ async fn handle_category(
     dialogue: QIFDialogue,
     msg: Message,
     manager_handle: Arc<ManagerHandle<TgManagerCommand>>,
-    (filename, item, items_left, items_processed): (
-        String,
+    (filenames, item, items_left, items_processed): (
+        Vec<String>,
         String,
         Vec<String>,
         HashMap<String, String>,
@@ -496,7 +1589,7 @@ async fn handle_category(
             .await?;
         dialogue
             .update(State::CategorySelect {
-                filename,
+                filenames,
                 item,
                 items_left,
                 items_processed,
@@ -516,32 +1609,27 @@ async fn handle_category(
     })
     .await?;
 
-    let user = response_rx.await.map_err(|_| {
-        log::warn!("No response for TgUserManager");
-        Box::new(UserManagerError::Request(
-            "No response for TgUserManager".to_string(),
-        ))
-    })?;
-
-    let mut accounts = if version.contains(':') {
-        filter_categories(user.accounts.iter(), &version.to_lowercase())
-    } else {
-        user.accounts
-            .iter()
-            .filter(|&e| {
-                e.starts_with("Expenses:") && e.to_lowercase().contains(&version.to_lowercase())
-            })
-            .collect::<Vec<_>>()
+    let user = match user_reply(msg.chat.id.0, response_rx).await {
+        Ok(user) => user,
+        Err(text) => {
+            bot.send_message(msg.chat.id, text).await?;
+            return Ok(());
+        }
     };
 
-    accounts.sort_unstable();
+    let results: Vec<String> = search_categories(&user.accounts, version)
+        .into_iter()
+        .cloned()
+        .collect();
+    drop(user);
+    let accounts: Vec<&String> = results.iter().collect();
 
     if accounts.is_empty() {
         bot.send_message(msg.chat.id, format!("Input subcategory for {}", item))
             .await?;
         dialogue
             .update(State::CategorySelect {
-                filename,
+                filenames,
                 item,
                 items_left,
                 items_processed,
@@ -550,17 +1638,13 @@ async fn handle_category(
         return Ok(());
     };
 
-    let keyboard = InlineKeyboardMarkup::default().append_row(
-        accounts
-            .into_iter()
-            .filter(|&l| l.starts_with("Expenses:"))
-            .map(|line| {
-                InlineKeyboardButton::new(
-                    line.strip_prefix("Expenses:").unwrap(),
-                    InlineKeyboardButtonKind::CallbackData(line.into()),
-                )
-            }),
-    );
+    // The results are kept in the dialogue to rebuild the keyboard
+    let parent = if accounts.len() > PagedKeyboard::new(vec![]).page_size() {
+        common_parent(&accounts)
+    } else {
+        String::new()
+    };
+    let keyboard = category_keyboard(&accounts, &parent, 0);
 
     bot.send_message(msg.chat.id, format!("Input subcategory for {}", item))
         .reply_markup(ReplyMarkup::InlineKeyboard(keyboard))
@@ -570,11 +1654,12 @@ async fn handle_category(
         Some(cat) => {
             dialogue
                 .update(State::SubCategorySelect {
-                    filename,
+                    filenames,
                     item,
                     category: cat.to_string(),
                     items_left,
                     items_processed,
+                    results,
                 })
                 .await?;
         }
@@ -585,17 +1670,21 @@ async fn handle_category(
     Ok(())
 }
 
+/// Fields of `State::SubCategorySelect` in the order of declaration
+type SubCategoryFields = (
+    Vec<String>,
+    String,
+    String,
+    Vec<String>,
+    HashMap<String, String>,
+    Vec<String>,
+);
+
 async fn handle_subcategory(
     bot: Bot,
     dialogue: QIFDialogue,
     msg: Message,
-    (filename, item, category, mut items_left, mut items_processed): (
-        String,
-        String,
-        String,
-        Vec<String>,
-        HashMap<String, String>,
-    ), // Available from `State::SubCategory`.
+    (filenames, item, category, mut items_left, mut items_processed, _results): SubCategoryFields,
 ) -> HandlerResult {
     log::debug!("SubCategory state");
     match msg.text() {
@@ -606,7 +1695,7 @@ async fn handle_subcategory(
                 if let Some(nextitem) = items_left.pop() {
                     dialogue
                         .update(State::CategorySelect {
-                            filename,
+                            filenames,
                             item: nextitem,
                             items_left,
                             items_processed,
@@ -637,10 +1726,41 @@ async fn handle_qif_ready(
     dialogue: QIFDialogue,
     msg: Message,
     manager_handle: Arc<ManagerHandle<TgManagerCommand>>,
-    (filename, item_categories): (String, HashMap<String, String>), // Available from `State::Ready`.
+    filters: Arc<FilterConfig>,
+    (filenames, item_categories, account): (Vec<String>, HashMap<String, String>, Option<String>), // Available from `State::Ready`.
 ) -> HandlerResult {
     log::debug!("QIF Ready state");
     let tx = &manager_handle.tx;
+
+    if let Some(gone) = filenames.iter().find(|f| !Path::new(f).exists()) {
+        log::warn!("Spooled receipt {} is gone", gone);
+        bot.send_message(msg.chat.id, "The receipts have expired, upload them again")
+            .await?;
+        for filename in &filenames {
+            remove_spooled(filename).await?;
+        }
+        dialogue
+            .update(State::NewJson {
+                filename: String::new(),
+            })
+            .await?;
+        return Ok(());
+    }
+    let purchases: Vec<_> = filenames.iter().map(|f| read_file(f)).collect();
+
+    // TODO: Check if we need to assign categories by default
+    if let Some((i, _)) = item_categories.iter().find(|(_, c)| c.is_empty()) {
+        log::error!("QIF is ready with no category for item {:}", i);
+        bot.send_message(msg.chat.id, "Internal error happened".to_string())
+            .await?;
+        dialogue
+            .update(State::NewJson {
+                filename: String::new(),
+            })
+            .await?;
+        return Ok(());
+    }
+
     let (response_tx, response_rx) = oneshot::channel();
 
     tx.send(TgManagerCommand::Get {
@@ -649,51 +1769,98 @@ async fn handle_qif_ready(
     })
     .await?;
 
-    let mut user = response_rx.await.map_err(|_| {
-        log::warn!("No response for TgUserManager");
-        Box::new(UserManagerError::Request(
-            "No response for TgUserManager".to_string(),
-        ))
-    })?;
-
-    let memo: &str = msg.text().unwrap_or("purchase");
-
-    let acc = Account::new()
-        .name("Reiffeisen")
-        .account_type(AccountType::Bank)
-        .build();
+    let mut user = match user_reply(msg.chat.id.0, response_rx).await {
+        Ok(user) => user,
+        Err(text) => {
+            bot.send_message(msg.chat.id, text).await?;
+            return Ok(());
+        }
+    };
 
-    // TODO: Check if we need to assign categories by default
-    for (i, c) in &item_categories {
-        if c.is_empty() {
-            log::error!("QIF is ready with no category for item {:}", i);
-            bot.send_message(msg.chat.id, "Internal error happened".to_string())
-                .await?;
-            dialogue
-                .update(State::NewJson {
-                    filename: String::new(),
-                })
-                .await?;
+    let acc = match user.payment_account(msg.chat.id.0, account.as_deref()) {
+        Some(acc) => acc,
+        None => {
+            drop(user);
+            bot.send_message(
+                msg.chat.id,
+                "Choose the account the purchase is paid from with the buttons above \
+                 or /setaccount, then enter the memo again",
+            )
+            .await?;
             return Ok(());
         }
+    };
+    let memo = match msg.text().map(str::trim) {
+        Some(text) if !text.is_empty() && text != "-" => text.to_string(),
+        _ => user.settings(msg.chat.id.0).memo,
+    };
 
-        categories::assign_category(i, c, &mut user.catmap);
+    let cat =
+        &|item: &str, _user: &mut User| -> String { item_categories.get(item).unwrap().to_owned() };
+
+    // All the receipts go into a single file
+    let mut qif = acc.to_string();
+    let mut learned = BTreeSet::new();
+    for purchase in &purchases {
+        let filter = filters.memo_filter(purchase.store())?.build();
+        let key_filter = filters.category_filter(purchase.store())?.build();
+        let t = convert(purchase, &memo, &mut user, &acc, filter, cat).unwrap();
+        qif.push_str(&t.to_string());
+        for i in &purchase.items {
+            if let Some(c) = item_categories.get(&i.name) {
+                learned.insert((key_filter(&i.name).into_owned(), c.clone()));
+            }
+        }
+    }
+    // The user is not kept locked while replying
+    drop(user);
+    bot.send_message(
+        msg.chat.id,
+        format!("QIF is ready for {} receipts.", purchases.len()),
+    )
+    .await?;
+    bot.send_document(msg.chat.id, InputFile::memory(qif.into_bytes()))
+        .await?;
+    let (response_tx, response_rx) = oneshot::channel();
+    tx.send(TgManagerCommand::Update {
+        user_id: msg.chat.id.0,
+        update: UserUpdate::MarkProcessed(purchases.iter().map(|p| p.id().to_string()).collect()),
+        reply_to: response_tx,
+    })
+    .await?;
+    if let Err(e) = response_rx.await? {
+        log::error!("Can't remember the receipts: {}", e);
+    }
+    for filename in &filenames {
+        remove_spooled(filename).await?;
     }
 
-    let cat = &|item: &str, _stats: &mut categories::CatStats, _acc: &HashSet<String>| -> String {
-        item_categories.get(item).unwrap().to_owned()
-    };
-
-    let filter = categories::LineFilter::new()
-        .numfilter()
-        .perekrestok_filter()
-        .trim_units_from_end()
-        .build();
+    for (item, category) in learned {
+        let (response_tx, response_rx) = oneshot::channel();
+        tx.send(TgManagerCommand::Update {
+            user_id: msg.chat.id.0,
+            update: UserUpdate::AssignCategory {
+                item: item.clone(),
+                category,
+            },
+            reply_to: response_tx,
+        })
+        .await?;
+        if let Err(e) = response_rx.await? {
+            log::error!("Can't learn category of {}: {}", item, e);
+        }
+    }
 
-    let t = convert(&filename, memo, &mut user, &acc, filter, cat).unwrap();
-    let qif = InputFile::memory(format!("{}{}", acc, t).into_bytes());
-    bot.send_message(msg.chat.id, "QIF is ready.").await?;
-    bot.send_document(msg.chat.id, qif).await?;
+    // Learned categories shouldn't wait for the periodic flush
+    let (response_tx, response_rx) = oneshot::channel();
+    tx.send(TgManagerCommand::Save {
+        user_id: msg.chat.id.0,
+        reply_to: response_tx,
+    })
+    .await?;
+    if let Err(e) = response_rx.await? {
+        log::error!("Can't save learned categories: {}", e);
+    }
 
     dialogue
         .update(State::NewJson {
@@ -704,11 +1871,158 @@ async fn handle_qif_ready(
     Ok(())
 }
 
-async fn callback_handler(q: CallbackQuery, bot: Bot, dialogue: QIFDialogue) -> HandlerResult {
-    if let Some(version) = q.data {
-        if version.starts_with("edit_") {
-            let item_id = version.strip_prefix("edit_").unwrap(); // Extract the item ID or number
+async fn callback_handler(
+    q: CallbackQuery,
+    bot: Bot,
+    dialogue: QIFDialogue,
+    manager_handle: Arc<ManagerHandle<TgManagerCommand>>,
+) -> HandlerResult {
+    if let Some(index) = q
+        .data
+        .as_deref()
+        .and_then(|d| d.strip_prefix(SOURCE_CALLBACK))
+    {
+        if let Some(Message { id, chat, .. }) = &q.message {
+            let name = match chosen_source(&manager_handle, chat.id, index).await? {
+                Some(name) => name,
+                None => {
+                    bot.edit_message_text(
+                        chat.id,
+                        *id,
+                        "The keyboard has expired, /setaccount again",
+                    )
+                    .await?;
+                    return Ok(());
+                }
+            };
+            let (response_tx, response_rx) = oneshot::channel();
+            manager_handle
+                .tx
+                .send(TgManagerCommand::Update {
+                    user_id: chat.id.0,
+                    update: UserUpdate::PaymentAccount {
+                        name: name.clone(),
+                        account_type: None,
+                    },
+                    reply_to: response_tx,
+                })
+                .await?;
+            let text = match response_rx.await? {
+                Ok(_) => format!("Purchases are paid from {}", name),
+                Err(e) => e.to_string(),
+            };
+            bot.edit_message_text(chat.id, *id, text).await?;
+        }
+        return Ok(());
+    }
+
+    if let Some(index) = q.data.as_deref().and_then(|d| d.strip_prefix(PAY_CALLBACK)) {
+        if let Some(Message { id, chat, .. }) = &q.message {
+            let name = chosen_source(&manager_handle, chat.id, index).await?;
+            match (dialogue.get().await?, name) {
+                (_, None) => {
+                    bot.send_message(chat.id, "The keyboard has expired, enter the memo line")
+                        .await?;
+                }
+                (
+                    Some(State::Ready {
+                        filenames,
+                        item_categories,
+                        ..
+                    }),
+                    Some(name),
+                ) => {
+                    dialogue
+                        .update(State::Ready {
+                            filenames,
+                            item_categories,
+                            account: Some(name.clone()),
+                        })
+                        .await?;
+                    bot.edit_message_text(
+                        chat.id,
+                        *id,
+                        format!("Paid from {}, enter the memo line or \"-\"", name),
+                    )
+                    .await?;
+                }
+                _ => {
+                    bot.send_message(chat.id, "No receipt is waiting for conversion")
+                        .await?;
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    // Both drilling down and paging rebuild the keyboard from the results
+    let view = q.data.as_deref().and_then(|d| {
+        d.strip_prefix(GROUP_CALLBACK)
+            .map(|parent| (0, parent))
+            .or_else(|| parse_page(d, CATEGORY_PAGE_CALLBACK))
+    });
+    if let Some((page, parent)) = view {
+        if let Some(Message { id, chat, .. }) = &q.message {
+            let keyboard = with_category_results(&dialogue, |results| {
+                let parent = parse_parent(results, parent)?;
+                Some(category_keyboard(results, &parent, page))
+            })
+            .await?;
+            match keyboard {
+                Some(keyboard) => {
+                    bot.edit_message_reply_markup(chat.id, *id)
+                        .reply_markup(keyboard)
+                        .await?;
+                }
+                None => {
+                    bot.edit_message_text(chat.id, *id, CATEGORY_KEYBOARD_EXPIRED)
+                        .await?;
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    if let Some((page, _)) = q
+        .data
+        .as_deref()
+        .and_then(|d| parse_page(d, ITEM_PAGE_CALLBACK))
+    {
+        if let Some(Message { id, chat, .. }) = &q.message {
+            if let Some(State::Ready {
+                item_categories, ..
+            }) = dialogue.get().await?
+            {
+                bot.edit_message_reply_markup(chat.id, *id)
+                    .reply_markup(items_keyboard(&item_categories, page))
+                    .await?;
+            }
+        }
+        return Ok(());
+    }
 
+    let choice = match q
+        .data
+        .as_deref()
+        .and_then(|d| d.strip_prefix(CATEGORY_CALLBACK))
+    {
+        Some(index) => {
+            let chosen =
+                with_category_results(&dialogue, |results| parse_category(results, index).cloned())
+                    .await?;
+            if chosen.is_none() {
+                if let Some(Message { id, chat, .. }) = &q.message {
+                    bot.edit_message_text(chat.id, *id, CATEGORY_KEYBOARD_EXPIRED)
+                        .await?;
+                }
+                return Ok(());
+            }
+            chosen
+        }
+        None => q.data.clone(),
+    };
+    if let Some(version) = choice {
+        if let Some(item_id) = version.strip_prefix(EDIT_CALLBACK) {
             // Process the selection, e.g., by updating the dialogue state or responding to the user
             let response_message = format!("Editing item {}", item_id);
             if let Some(chat_id) = q.message.clone().map(|msg| msg.chat.id) {
@@ -718,13 +2032,16 @@ async fn callback_handler(q: CallbackQuery, bot: Bot, dialogue: QIFDialogue) ->
                 if let Some(data) = state {
                     log::info!("State: {}", data);
                     if let State::Ready {
-                        filename,
+                        filenames,
                         item_categories,
+                        ..
                     } = data
                     {
                         let mut item_to_edit = None;
                         let req_item: usize = item_id.parse().unwrap_or_default();
-                        for (index, (key, value)) in item_categories.iter().enumerate() {
+                        for (index, (key, value)) in
+                            sorted_items(&item_categories).into_iter().enumerate()
+                        {
                             log::debug!("Index: {}, Key: {}, Value: {}", index, key, value);
                             if index == req_item {
                                 log::info!("Editing item {}:{}", key, value);
@@ -740,7 +2057,7 @@ async fn callback_handler(q: CallbackQuery, bot: Bot, dialogue: QIFDialogue) ->
                             .await?;
                             dialogue
                                 .update(State::CategorySelect {
-                                    filename,
+                                    filenames,
                                     item: key,
                                     items_left: vec![],
                                     items_processed: item_categories,
@@ -763,11 +2080,11 @@ async fn callback_handler(q: CallbackQuery, bot: Bot, dialogue: QIFDialogue) ->
                 if let Some(data) = state {
                     log::info!("Data: {}", data);
                     if let State::SubCategorySelect {
-                        filename,
+                        filenames,
                         item,
-                        category: _,
                         mut items_left,
                         mut items_processed,
+                        ..
                     } = data
                     {
                         log::info!("SubCategory match!");
@@ -785,7 +2102,7 @@ async fn callback_handler(q: CallbackQuery, bot: Bot, dialogue: QIFDialogue) ->
                             .await?;
                             dialogue
                                 .update(State::CategorySelect {
-                                    filename,
+                                    filenames,
                                     item: newitem,
                                     items_left,
                                     items_processed,
@@ -798,15 +2115,29 @@ async fn callback_handler(q: CallbackQuery, bot: Bot, dialogue: QIFDialogue) ->
                                 chat.id,
                                 "Items are categorized and categories are updated".to_string(),
                             )
-                            .reply_markup(create_categories_keyboard(&items_processed))
+                            .reply_markup(items_keyboard(&items_processed, 0))
                             .await?;
 
-                            bot.send_message(chat.id, "Enter the memo line".to_string())
+                            let (response_tx, response_rx) = oneshot::channel();
+                            manager_handle
+                                .tx
+                                .send(TgManagerCommand::Get {
+                                    user_id: chat.id.0,
+                                    reply_to: response_tx,
+                                })
+                                .await?;
+                            let (text, keyboard) = match user_reply(chat.id.0, response_rx).await {
+                                Ok(user) => memo_prompt(&user, chat.id.0),
+                                Err(text) => (text, InlineKeyboardMarkup::default()),
+                            };
+                            bot.send_message(chat.id, text)
+                                .reply_markup(keyboard)
                                 .await?;
                             dialogue
                                 .update(State::Ready {
-                                    filename,
+                                    filenames,
                                     item_categories: items_processed,
+                                    account: None,
                                 })
                                 .await?;
                         }
@@ -826,24 +2157,59 @@ async fn callback_handler(q: CallbackQuery, bot: Bot, dialogue: QIFDialogue) ->
     Ok(())
 }
 
+/// How often abandoned received files are looked for
+#[cfg(feature = "telegram")]
+const SWEEP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
 #[cfg(feature = "telegram")]
-async fn run() {
+async fn run(filters: FilterConfig) {
     #[cfg(feature = "monitoring")]
     let monitoring_handle = tokio::spawn(async move { monitoring::web_main().await });
 
     log::info!("Starting telegram bot");
+    let households = match Households::new(&None) {
+        Ok(households) => households,
+        Err(e) => {
+            log::error!("{}", e);
+            return;
+        }
+    };
     let (tx, mut rx) = mpsc::channel(32);
 
-    let manager = tokio::spawn(async move { user_manager(&mut rx).await });
+    let manager_filters = filters.clone();
+    let manager =
+        tokio::spawn(async move { user_manager(&mut rx, households, manager_filters).await });
 
     let manager_handle = Arc::new(ManagerHandle { tx });
+    let filters = Arc::new(filters);
+    let storage = match FileStorage::<State>::new(&None) {
+        Ok(storage) => storage,
+        Err(e) => {
+            log::error!("Can't open dialogue storage: {}", e);
+            return;
+        }
+    };
+
+    // The first tick sweeps the files left by the previous run
+    let sweeper = tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(SWEEP_INTERVAL);
+        loop {
+            ticker.tick().await;
+            match tokio::task::spawn_blocking(|| sweep_spool(SPOOL_TTL)).await {
+                Ok(Ok(0)) => (),
+                Ok(Ok(removed)) => log::info!("Removed {} abandoned files", removed),
+                Ok(Err(e)) => log::error!("Can't sweep received files: {}", e),
+                Err(e) => log::error!("Sweep failed: {}", e),
+            }
+        }
+    });
 
     let bot = Bot::from_env();
 
     let handler = dptree::entry()
         .branch(
             Update::filter_message()
-                .enter_dialogue::<Message, InMemStorage<State>, State>()
+                .enter_dialogue::<Message, FileStorage<State>, State>()
                 .branch(
                     dptree::entry()
                         // Filter commands: the next handlers will receive a parsed `Command`.
@@ -851,11 +2217,17 @@ async fn run() {
                         // If a command parsing fails, this handler will not be executed.
                         .endpoint(command_handler),
                 )
+                .branch(
+                    dptree::filter(|msg: Message, state: State| {
+                        msg.document().is_some() && state.is_session()
+                    })
+                    .endpoint(handle_batch_json),
+                )
                 .branch(dptree::case![State::Idle].endpoint(handle_idle))
                 .branch(dptree::case![State::NewJson { filename }].endpoint(handle_json))
                 .branch(
                     dptree::case![State::CategorySelect {
-                        filename,
+                        filenames,
                         item,
                         items_left,
                         items_processed,
@@ -864,35 +2236,50 @@ async fn run() {
                 )
                 .branch(
                     dptree::case![State::SubCategorySelect {
-                        filename,
+                        filenames,
                         item,
                         category,
                         items_left,
                         items_processed,
+                        results
                     }]
                     .endpoint(handle_subcategory),
                 )
                 .branch(
                     dptree::case![State::Ready {
-                        filename,
-                        item_categories
+                        filenames,
+                        item_categories,
+                        account
                     }]
                     .endpoint(handle_qif_ready),
-                ),
+                )
+                .branch(
+                    dptree::case![State::ImportKnowledge { mode }]
+                        .endpoint(handle_import_knowledge),
+                )
+                .branch(dptree::case![State::StartAccounts].endpoint(handle_start_accounts))
+                .branch(dptree::case![State::StartSource].endpoint(handle_start_source))
+                .branch(dptree::case![State::DeleteConfirm].endpoint(handle_delete_confirm)),
         )
         .branch(
             Update::filter_callback_query()
-                .enter_dialogue::<CallbackQuery, InMemStorage<State>, State>()
+                .enter_dialogue::<CallbackQuery, FileStorage<State>, State>()
                 .endpoint(callback_handler),
         );
     Dispatcher::builder(bot, handler)
-        .dependencies(dptree::deps![InMemStorage::<State>::new(), manager_handle])
+        .dependencies(dptree::deps![storage, manager_handle, filters])
         .enable_ctrlc_handler()
         .build()
         .dispatch()
         .await;
 
-    drop(manager);
+    // The dispatcher owned the last sender, so the manager saves the cached
+    // users and stops
+    sweeper.abort();
+    match manager.await {
+        Ok(()) => log::info!("User manager stopped"),
+        Err(e) => log::error!("User manager panicked: {:?}", e),
+    }
     #[cfg(feature = "monitoring")]
     monitoring_handle.await.unwrap();
 }
@@ -900,10 +2287,25 @@ async fn run() {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::tgkeyboard::MAX_CALLBACK_DATA;
+
+    #[test]
+    fn test_common_parent() {
+        let names = [
+            "Expenses:Food:Bread".to_string(),
+            "Expenses:Food:Dairy:Milk".to_string(),
+            "Expenses:Food".to_string(),
+        ];
+        let names: Vec<&String> = names.iter().collect();
+        assert_eq!(common_parent(&names[..2]), "Expenses:Food");
+        assert_eq!(common_parent(&names), "Expenses");
+        assert_eq!(common_parent(&names[2..]), "Expenses");
+        assert_eq!(common_parent(&[]), "");
+    }
 
     #[test]
     fn test_filter_categories_basic_matching() {
-        let categories = vec![
+        let categories = [
             "seg1:seg2:seg3".to_string(),
             "seg1:segX:seg3".to_string(),
             "segA:seg2:segB".to_string(),
@@ -917,7 +2319,7 @@ mod tests {
 
     #[test]
     fn test_filter_categories_partial_match() {
-        let categories = vec![
+        let categories = [
             "seg1:seg2:seg3".to_string(),
             "seg1:seg2X:seg3".to_string(),
             "seg1:seg2".to_string(),
@@ -932,7 +2334,7 @@ mod tests {
 
     #[test]
     fn test_filter_categories_empty_input() {
-        let categories = vec!["seg1:seg2:seg3".to_string(), "seg4:seg5:seg6".to_string()];
+        let categories = ["seg1:seg2:seg3".to_string(), "seg4:seg5:seg6".to_string()];
 
         let filtered = filter_categories(categories.iter(), "");
         assert!(filtered.is_empty());
@@ -940,7 +2342,7 @@ mod tests {
 
     #[test]
     fn test_filter_categories_no_match() {
-        let categories = vec!["seg1:seg2:seg3".to_string(), "seg4:seg5:seg6".to_string()];
+        let categories = ["seg1:seg2:seg3".to_string(), "seg4:seg5:seg6".to_string()];
 
         let filtered = filter_categories(categories.iter(), "segX:segY");
         assert!(filtered.is_empty());
@@ -948,7 +2350,7 @@ mod tests {
 
     #[test]
     fn test_filter_categories_single_segment_input() {
-        let categories = vec![
+        let categories = [
             "seg1:seg2:seg3".to_string(),
             "seg1:seg4:seg5".to_string(),
             "segX:segY:segZ".to_string(),
@@ -957,4 +2359,91 @@ mod tests {
         let filtered = filter_categories(categories.iter(), "seg1");
         assert_eq!(filtered, vec![&categories[0], &categories[1]]);
     }
+
+    #[test]
+    fn test_check_upload() {
+        assert_eq!(check_upload(Some("receipt.JSON"), None, 5000), Ok(()));
+        assert_eq!(
+            check_upload(Some("receipt"), Some("application/json"), 5000),
+            Ok(())
+        );
+        assert_eq!(
+            check_upload(Some("receipt.pdf"), Some("application/pdf"), 5000),
+            Err(UploadError::NotJson)
+        );
+        assert_eq!(
+            check_upload(None, Some("text/plain"), 5000),
+            Err(UploadError::NotJson)
+        );
+        assert_eq!(
+            check_upload(Some("receipt.json"), None, 20 * 1024 * 1024),
+            Err(UploadError::TooLarge)
+        );
+    }
+
+    #[test]
+    fn test_source_keyboard() {
+        let sources = vec![
+            "Активы:Текущие активы:Банковские карты:Зарплатная карта Сбербанка".to_string(),
+            "Assets:Wallet".to_string(),
+        ];
+        let keyboard = source_keyboard(&sources, SOURCE_CALLBACK).inline_keyboard;
+        assert_eq!(keyboard[0][0].text, sources[0]);
+        for button in keyboard.iter().flatten() {
+            match &button.kind {
+                InlineKeyboardButtonKind::CallbackData(data) => {
+                    assert!(data.len() <= MAX_CALLBACK_DATA)
+                }
+                _ => panic!("Not a callback button"),
+            }
+        }
+        assert!(matches!(
+            &keyboard[1][0].kind,
+            InlineKeyboardButtonKind::CallbackData(data)
+                if data == &format!("source:{}:1", names_digest(&sources))
+        ));
+    }
+
+    #[test]
+    fn test_check_data_upload() {
+        assert_eq!(
+            check_data_upload(Some("cats.CSV"), 5000, &KNOWLEDGE_EXTENSIONS),
+            Ok(())
+        );
+        assert_eq!(
+            check_data_upload(Some("main.journal"), 5000, &ACCOUNT_EXTENSIONS),
+            Ok(())
+        );
+        assert_eq!(
+            check_data_upload(Some("main.journal"), 5000, &KNOWLEDGE_EXTENSIONS),
+            Err(UploadError::Extension("json, csv".to_string()))
+        );
+        assert!(check_data_upload(None, 5000, &ACCOUNT_EXTENSIONS).is_err());
+        assert_eq!(
+            check_data_upload(Some("cats.json"), 20 * 1024 * 1024, &KNOWLEDGE_EXTENSIONS),
+            Err(UploadError::DataTooLarge)
+        );
+    }
+
+    #[test]
+    fn test_merge_items() {
+        let mut processed = HashMap::from([("milk".to_string(), "Expenses:Dairy".to_string())]);
+        let mut left = vec!["bread".to_string()];
+        let other = (
+            HashMap::from([
+                ("milk".to_string(), "Expenses:Food".to_string()),
+                ("tea".to_string(), "Expenses:Drinks".to_string()),
+            ]),
+            vec![
+                "bread".to_string(),
+                "cheese".to_string(),
+                "eggs".to_string(),
+                "milk".to_string(),
+            ],
+        );
+        merge_items(&mut processed, &mut left, Some("eggs"), other);
+        assert_eq!(processed["milk"], "Expenses:Dairy");
+        assert_eq!(processed["tea"], "Expenses:Drinks");
+        assert_eq!(left, vec!["bread", "cheese"]);
+    }
 }
@@ -0,0 +1,442 @@
+use crate::accounts::{children, Level};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup};
+
+/// Callback data prefix for parent account buttons, followed by the parent
+/// reference made by `parent_data`
+pub const GROUP_CALLBACK: &str = "group:";
+
+/// Callback data prefix for paging through the accounts under a parent
+pub const CATEGORY_PAGE_CALLBACK: &str = "catpage:";
+
+/// Callback data prefix for the chosen category, followed by the digest of
+/// the search results and the index in them
+pub const CATEGORY_CALLBACK: &str = "cat:";
+
+/// Telegram refuses buttons with longer callback data
+#[cfg(test)]
+pub const MAX_CALLBACK_DATA: usize = 64;
+
+/// Callback data prefix for paging through the receipt items
+pub const ITEM_PAGE_CALLBACK: &str = "itempage:";
+
+/// Callback data prefix for editing the item category, followed by its index
+pub const EDIT_CALLBACK: &str = "edit_";
+
+/// Prefix of the accounts offered as categories, omitted on the buttons
+const CATEGORY_ROOT: &str = "Expenses:";
+
+/// Inline keyboard split into pages of `columns` x `rows` buttons with
+/// previous/next buttons
+pub struct PagedKeyboard {
+    buttons: Vec<InlineKeyboardButton>,
+    columns: usize,
+    rows: usize,
+    footer: Vec<InlineKeyboardButton>,
+}
+
+impl PagedKeyboard {
+    pub fn new(buttons: Vec<InlineKeyboardButton>) -> Self {
+        PagedKeyboard {
+            buttons,
+            columns: 2,
+            rows: 5,
+            footer: vec![],
+        }
+    }
+
+    pub fn columns(mut self, columns: usize) -> Self {
+        self.columns = columns.max(1);
+        self
+    }
+
+    pub fn rows(mut self, rows: usize) -> Self {
+        self.rows = rows.max(1);
+        self
+    }
+
+    /// Button shown on every page below the navigation
+    pub fn footer(mut self, button: InlineKeyboardButton) -> Self {
+        self.footer.push(button);
+        self
+    }
+
+    pub fn page_size(&self) -> usize {
+        self.columns * self.rows
+    }
+
+    pub fn pages(&self) -> usize {
+        self.buttons.len().div_ceil(self.page_size()).max(1)
+    }
+
+    /// Keyboard with the `page`, the last one if it's out of range.
+    /// `page_data` makes callback data for switching to the other page.
+    pub fn build<F>(&self, page: usize, page_data: F) -> InlineKeyboardMarkup
+    where
+        F: Fn(usize) -> String,
+    {
+        let page = page.min(self.pages() - 1);
+        let mut keyboard = InlineKeyboardMarkup::default();
+        let start = page * self.page_size();
+        let end = (start + self.page_size()).min(self.buttons.len());
+        for row in self.buttons[start..end].chunks(self.columns) {
+            keyboard = keyboard.append_row(row.to_vec());
+        }
+
+        let mut navigation = vec![];
+        if page > 0 {
+            navigation.push(InlineKeyboardButton::callback(
+                format!("‹ {}/{}", page, self.pages()),
+                page_data(page - 1),
+            ));
+        }
+        if page + 1 < self.pages() {
+            navigation.push(InlineKeyboardButton::callback(
+                format!("{}/{} ›", page + 2, self.pages()),
+                page_data(page + 1),
+            ));
+        }
+        if !navigation.is_empty() {
+            keyboard = keyboard.append_row(navigation);
+        }
+        if !self.footer.is_empty() {
+            keyboard = keyboard.append_row(self.footer.clone());
+        }
+        keyboard
+    }
+}
+
+/// Callback data switching to `page`, `payload` identifies the keyboard
+pub fn page_data(prefix: &str, page: usize, payload: &str) -> String {
+    format!("{}{}:{}", prefix, page, payload)
+}
+
+/// Page and payload from the callback data made by `page_data`
+pub fn parse_page<'a>(data: &'a str, prefix: &str) -> Option<(usize, &'a str)> {
+    let (page, payload) = data.strip_prefix(prefix)?.split_once(':')?;
+    Some((page.parse().ok()?, payload))
+}
+
+/// Button text for the account under `parent`
+fn account_label<'a>(name: &'a str, parent: &str) -> &'a str {
+    let prefix = if parent.is_empty() {
+        CATEGORY_ROOT.to_string()
+    } else {
+        format!("{}:", parent)
+    };
+    match name.strip_prefix(&prefix) {
+        Some(label) if !label.is_empty() => label,
+        _ => name.trim_start_matches(CATEGORY_ROOT),
+    }
+}
+
+/// Short digest of the `names` buttons refer to by index. It's put into the
+/// callback data to tell the buttons made for a different list.
+pub fn names_digest<S: AsRef<str>>(names: &[S]) -> String {
+    let mut hasher = DefaultHasher::new();
+    for name in names {
+        name.as_ref().hash(&mut hasher);
+    }
+    format!("{:08x}", hasher.finish() as u32)
+}
+
+/// Index into `names` from the callback `data` made as "digest:index",
+/// None if the button was made for a different list
+pub fn parse_index<S: AsRef<str>>(names: &[S], data: &str) -> Option<usize> {
+    let (digest, index) = data.split_once(':')?;
+    let index = index.parse().ok()?;
+    (digest == names_digest(names) && index < names.len()).then_some(index)
+}
+
+/// Account names don't fit into the callback data, so the parent is
+/// referred to by the first search result under it and its depth, after
+/// the `digest` of the results
+fn parent_data(results: &[&String], digest: &str, parent: &str) -> String {
+    if parent.is_empty() {
+        return format!("{}:0:0", digest);
+    }
+    let prefix = format!("{}:", parent);
+    let index = results
+        .iter()
+        .position(|name| name.starts_with(&prefix))
+        .unwrap_or_default();
+    format!("{}:{}:{}", digest, index, parent.split(':').count())
+}
+
+/// Parent account referred to by `data` made by `parent_data`, None if the
+/// keyboard was made for other results
+pub fn parse_parent(results: &[&String], data: &str) -> Option<String> {
+    let (digest, data) = data.split_once(':')?;
+    if digest != names_digest(results) {
+        return None;
+    }
+    let (index, depth) = data.split_once(':')?;
+    let (index, depth): (usize, usize) = (index.parse().ok()?, depth.parse().ok()?);
+    if depth == 0 {
+        return Some(String::new());
+    }
+    let parts: Vec<&str> = results.get(index)?.split(':').collect();
+    if depth >= parts.len() {
+        return None;
+    }
+    Some(parts[..depth].join(":"))
+}
+
+/// Search result chosen by the callback `data` of the category button,
+/// None if the keyboard was made for other results
+pub fn parse_category<'a>(results: &[&'a String], data: &str) -> Option<&'a String> {
+    Some(results[parse_index(results, data)?])
+}
+
+/// Keyboard with the search `results`. If they don't fit a single page,
+/// there are postable categories and parents to drill down into under
+/// `parent`, which is empty at the top level. The buttons refer to the
+/// results by index, so the keyboard is to be rebuilt from the same results.
+pub fn category_keyboard(results: &[&String], parent: &str, page: usize) -> InlineKeyboardMarkup {
+    let digest = names_digest(results);
+    let mut keyboard = PagedKeyboard::new(vec![]);
+    let (levels, parent) = if results.len() <= keyboard.page_size() {
+        let leaves = results.iter().map(|&name| Level::Leaf(name.clone()));
+        (leaves.collect(), "")
+    } else {
+        (children(results.iter().copied(), parent), parent)
+    };
+    keyboard.buttons = levels
+        .iter()
+        .map(|level| match level {
+            Level::Group(group) => InlineKeyboardButton::callback(
+                format!("{} ›", account_label(group, parent)),
+                format!("{}{}", GROUP_CALLBACK, parent_data(results, &digest, group)),
+            ),
+            Level::Leaf(leaf) => {
+                let index = results.iter().position(|&name| name == leaf);
+                InlineKeyboardButton::callback(
+                    account_label(leaf, parent),
+                    format!(
+                        "{}{}:{}",
+                        CATEGORY_CALLBACK,
+                        digest,
+                        index.unwrap_or_default()
+                    ),
+                )
+            }
+        })
+        .collect();
+    if !parent.is_empty() {
+        let up = parent.rsplit_once(':').map_or("", |(up, _)| up);
+        keyboard = keyboard.footer(InlineKeyboardButton::callback(
+            "‹ Back",
+            format!("{}{}", GROUP_CALLBACK, parent_data(results, &digest, up)),
+        ));
+    }
+    let parent = parent_data(results, &digest, parent);
+    keyboard.build(page, |p| page_data(CATEGORY_PAGE_CALLBACK, p, &parent))
+}
+
+/// Items in the order of the item keyboard, the edit buttons refer to the
+/// position in this list
+pub fn sorted_items(catitems: &HashMap<String, String>) -> Vec<(&String, &String)> {
+    let mut items: Vec<_> = catitems.iter().collect();
+    items.sort();
+    items
+}
+
+/// "Expenses:Food:Dairy" is shortened to "E:F:Dairy"
+fn short_category(category: &str) -> String {
+    let parts: Vec<&str> = category.split(':').collect();
+    if parts.len() > 1 {
+        parts[..parts.len() - 1]
+            .iter()
+            .map(|&part| {
+                part.chars()
+                    .next()
+                    .unwrap_or_default()
+                    .to_uppercase()
+                    .collect::<String>()
+            })
+            .chain(std::iter::once(parts.last().unwrap().to_string()))
+            .collect::<Vec<String>>()
+            .join(":")
+    } else {
+        category.to_string()
+    }
+}
+
+/// Keyboard with the items and their categories to edit them
+pub fn items_keyboard(catitems: &HashMap<String, String>, page: usize) -> InlineKeyboardMarkup {
+    let buttons = sorted_items(catitems)
+        .into_iter()
+        .enumerate()
+        .map(|(index, (item, category))| {
+            let button_text = format!("{}: {}", item, short_category(category));
+            // Using only the index as callback data to avoid exceeding the maximum length
+            let callback_data = format!("{}{}", EDIT_CALLBACK, index);
+            log::info!("Text: '{}'  Data: '{}'", button_text, callback_data);
+            InlineKeyboardButton::callback(button_text, callback_data)
+        })
+        .collect();
+    PagedKeyboard::new(buttons)
+        .columns(1)
+        .rows(8)
+        .build(page, |p| page_data(ITEM_PAGE_CALLBACK, p, ""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use teloxide::types::InlineKeyboardButtonKind;
+
+    fn data(button: &InlineKeyboardButton) -> &str {
+        match &button.kind {
+            InlineKeyboardButtonKind::CallbackData(data) => data,
+            _ => panic!("Not a callback button"),
+        }
+    }
+
+    #[test]
+    fn test_paging() {
+        let buttons = (0..11)
+            .map(|i| InlineKeyboardButton::callback(i.to_string(), i.to_string()))
+            .collect();
+        let keyboard = PagedKeyboard::new(buttons).columns(3).rows(2);
+        assert_eq!(keyboard.pages(), 2);
+
+        let first = keyboard
+            .build(0, |p| page_data("p:", p, "x"))
+            .inline_keyboard;
+        assert_eq!(first.len(), 3);
+        assert_eq!(first[0].len(), 3);
+        assert_eq!(data(&first[2][0]), "p:1:x");
+
+        let last = keyboard
+            .build(5, |p| page_data("p:", p, "x"))
+            .inline_keyboard;
+        assert_eq!(last[0].len(), 3);
+        assert_eq!(last[1].len(), 2);
+        assert_eq!(data(&last[1][1]), "10");
+        assert_eq!(data(&last[2][0]), "p:0:x");
+
+        assert_eq!(
+            parse_page("p:1:Expenses:Food", "p:"),
+            Some((1, "Expenses:Food"))
+        );
+        assert_eq!(parse_page("p:x:Food", "p:"), None);
+        assert_eq!(parse_page("group:Food", "p:"), None);
+    }
+
+    #[test]
+    fn test_drill_down() {
+        let names: Vec<String> = (0..12)
+            .map(|i| format!("Expenses:Food:Dairy:{:02}", i))
+            .chain(["Expenses:Food:Bread".to_string()])
+            .collect();
+        let mut results: Vec<&String> = names.iter().collect();
+        results.sort();
+        let d = names_digest(&results);
+        let keyboard = category_keyboard(&results, "Expenses:Food", 0).inline_keyboard;
+        assert_eq!(keyboard[0][0].text, "Dairy ›");
+        assert_eq!(data(&keyboard[0][0]), format!("group:{}:1:3", d));
+        assert_eq!(
+            parse_parent(&results, &format!("{}:1:3", d)).as_deref(),
+            Some("Expenses:Food:Dairy")
+        );
+        assert_eq!(keyboard[0][1].text, "Bread");
+        assert_eq!(data(&keyboard[0][1]), format!("cat:{}:0", d));
+        assert_eq!(
+            parse_category(&results, &format!("{}:0", d)),
+            Some(results[0])
+        );
+        assert_eq!(parse_category(&results, &format!("{}:13", d)), None);
+        assert_eq!(data(&keyboard[1][0]), format!("group:{}:0:1", d));
+        assert_eq!(
+            parse_parent(&results, &format!("{}:0:1", d)).as_deref(),
+            Some("Expenses")
+        );
+
+        // Pages are rebuilt from the same results
+        let dairy = category_keyboard(&results, "Expenses:Food:Dairy", 0).inline_keyboard;
+        assert_eq!(dairy[0][0].text, "00");
+        assert_eq!(data(&dairy[0][0]), format!("cat:{}:1", d));
+        let (page, parent) = parse_page(data(&dairy[5][0]), CATEGORY_PAGE_CALLBACK).unwrap();
+        let parent = parse_parent(&results, parent).unwrap();
+        let next = category_keyboard(&results, &parent, page).inline_keyboard;
+        assert_eq!(next[0][0].text, "10");
+        assert_eq!(data(&next[0][1]), format!("cat:{}:12", d));
+
+        let top = category_keyboard(&results, "", 0);
+        assert_eq!(top.inline_keyboard.len(), 1);
+        assert_eq!(top.inline_keyboard[0][0].text, "Expenses ›");
+        assert_eq!(
+            parse_parent(&results, &format!("{}:0:0", d)).as_deref(),
+            Some("")
+        );
+        assert_eq!(parse_parent(&results, &format!("{}:0:4", d)), None);
+        assert_eq!(parse_parent(&results, &format!("{}:99:1", d)), None);
+
+        // Few results are offered as is
+        let few = category_keyboard(&results[..2], "", 0).inline_keyboard;
+        assert_eq!(few[0][0].text, "Food:Bread");
+        assert_eq!(few[0][1].text, "Food:Dairy:00");
+        assert_eq!(
+            data(&few[0][1]),
+            format!("cat:{}:1", names_digest(&results[..2]))
+        );
+    }
+
+    #[test]
+    fn test_stale_buttons() {
+        let names = vec![
+            "Expenses:Food".to_string(),
+            "Expenses:Transport".to_string(),
+        ];
+        let results: Vec<&String> = names.iter().collect();
+        let keyboard = category_keyboard(&results, "", 0).inline_keyboard;
+        let index = data(&keyboard[0][1])
+            .strip_prefix(CATEGORY_CALLBACK)
+            .unwrap();
+        assert_eq!(parse_category(&results, index), Some(results[1]));
+
+        // A new account shifts the indices, the old buttons are refused
+        let added = "Expenses:Books".to_string();
+        let mut changed = vec![&added];
+        changed.extend(&results);
+        assert_eq!(parse_category(&changed, index), None);
+        assert_eq!(parse_index(&names, "0"), None);
+        assert_eq!(
+            parse_index(&names, &format!("{}:1", names_digest(&names))),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn test_callback_data_size() {
+        let group = "Расходы:Продукты питания:Молочные продукты и яйца";
+        let names: Vec<String> = (0..30)
+            .map(|i| format!("{}:Йогурты и творожки питьевые {}", group, i))
+            .collect();
+        let results: Vec<&String> = names.iter().collect();
+        for parent in ["", "Расходы", "Расходы:Продукты питания", group]
+        {
+            for page in 0..3 {
+                let keyboard = category_keyboard(&results, parent, page);
+                for button in keyboard.inline_keyboard.iter().flatten() {
+                    assert!(data(button).len() <= MAX_CALLBACK_DATA, "{}", data(button));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_items() {
+        let items = HashMap::from([
+            ("tea".to_string(), "Expenses:Food:Drinks".to_string()),
+            ("bread".to_string(), "Expenses:Food".to_string()),
+        ]);
+        let keyboard = items_keyboard(&items, 0).inline_keyboard;
+        assert_eq!(keyboard[0][0].text, "bread: E:Food");
+        assert_eq!(keyboard[1][0].text, "tea: E:F:Drinks");
+        assert_eq!(data(&keyboard[1][0]), "edit_1");
+    }
+}
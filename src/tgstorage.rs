@@ -0,0 +1,370 @@
+use crate::user::DEFAULT_DB_PATH;
+use futures::future::BoxFuture;
+use serde::{de::DeserializeOwned, Serialize};
+use shellexpand::tilde;
+use std::io::ErrorKind;
+use std::marker::PhantomData;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use teloxide::dispatching::dialogue::Storage;
+use teloxide::types::ChatId;
+use thiserror::Error;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+/// Directory with dialogue states next to the user databases
+pub const DIALOGUES_DIR: &str = "dialogues";
+
+/// Directory with received files next to the user databases
+pub const SPOOL_DIR: &str = "spool";
+
+/// Received files left for this long are considered abandoned
+pub const SPOOL_TTL: Duration = Duration::from_secs(2 * 24 * 60 * 60);
+
+#[derive(Debug, Error)]
+pub enum FileStorageError {
+    #[error("Dialogue not found")]
+    DialogueNotFound,
+    #[error("Can't access dialogue file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Can't serialize dialogue: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+/// Dialogue storage keeping a JSON file per chat, so the sessions survive
+/// bot restarts
+pub struct FileStorage<D> {
+    dir: PathBuf,
+    dialogue: PhantomData<fn() -> D>,
+}
+
+impl<D> FileStorage<D> {
+    pub fn new(dir: &Option<String>) -> Result<Arc<Self>, FileStorageError> {
+        let path = match dir {
+            Some(path) => path.to_string(),
+            None => DEFAULT_DB_PATH.to_owned() + DIALOGUES_DIR,
+        };
+        let dir = PathBuf::from(tilde(&path).as_ref());
+        std::fs::create_dir_all(&dir)?;
+        restrict(&dir, 0o700)?;
+        Ok(Arc::new(FileStorage {
+            dir,
+            dialogue: PhantomData,
+        }))
+    }
+
+    fn path(&self, chat_id: ChatId) -> PathBuf {
+        self.dir.join(format!("{}.json", chat_id.0))
+    }
+}
+
+impl<D> Storage<D> for FileStorage<D>
+where
+    D: Serialize + DeserializeOwned + Send + 'static,
+{
+    type Error = FileStorageError;
+
+    fn remove_dialogue(
+        self: Arc<Self>,
+        chat_id: ChatId,
+    ) -> BoxFuture<'static, Result<(), Self::Error>>
+    where
+        D: Send + 'static,
+    {
+        Box::pin(async move {
+            match fs::remove_file(self.path(chat_id)).await {
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    Err(FileStorageError::DialogueNotFound)
+                }
+                result => Ok(result?),
+            }
+        })
+    }
+
+    fn update_dialogue(
+        self: Arc<Self>,
+        chat_id: ChatId,
+        dialogue: D,
+    ) -> BoxFuture<'static, Result<(), Self::Error>>
+    where
+        D: Send + 'static,
+    {
+        Box::pin(async move {
+            let data = serde_json::to_vec(&dialogue)?;
+            // Interrupted write must not leave the dialogue half-written
+            let path = self.path(chat_id);
+            let temp = path.with_extension("json.tmp");
+            let mut file = create_spooled(&temp).await?;
+            file.write_all(&data).await?;
+            file.sync_all().await?;
+            drop(file);
+            fs::rename(&temp, &path).await?;
+            Ok(())
+        })
+    }
+
+    fn get_dialogue(
+        self: Arc<Self>,
+        chat_id: ChatId,
+    ) -> BoxFuture<'static, Result<Option<D>, Self::Error>> {
+        Box::pin(async move {
+            let path = self.path(chat_id);
+            let data = match fs::read(&path).await {
+                Ok(data) => data,
+                Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+            match serde_json::from_slice(&data) {
+                Ok(dialogue) => Ok(Some(dialogue)),
+                Err(e) => {
+                    // The state may be left by an older version, start over
+                    log::warn!("Dropping unreadable dialogue of {}: {}", chat_id, e);
+                    fs::remove_file(&path).await?;
+                    Ok(None)
+                }
+            }
+        })
+    }
+}
+
+fn spool_root() -> PathBuf {
+    let path = DEFAULT_DB_PATH.to_owned() + SPOOL_DIR;
+    PathBuf::from(tilde(&path).as_ref())
+}
+
+/// Directory for the files received from `chat_id`
+pub fn spool_dir(chat_id: i64) -> PathBuf {
+    spool_root().join(chat_id.to_string())
+}
+
+/// Purchases and dialogues are private, nobody but the bot may look into
+/// them
+fn restrict(path: &Path, mode: u32) -> std::io::Result<()> {
+    #[cfg(unix)]
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    #[cfg(not(unix))]
+    let _ = (path, mode);
+    Ok(())
+}
+
+/// Create the spool of `chat_id` accessible by the bot only
+pub fn create_spool(chat_id: i64) -> std::io::Result<PathBuf> {
+    let dir = spool_dir(chat_id);
+    std::fs::create_dir_all(&dir)?;
+    restrict(&spool_root(), 0o700)?;
+    restrict(&dir, 0o700)?;
+    Ok(dir)
+}
+
+/// Create the file to be spooled or stored readable by the bot only
+pub async fn create_spooled(path: &Path) -> std::io::Result<fs::File> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    options.open(path).await
+}
+
+/// Remove the processed file, it may be swept already
+pub async fn remove_spooled(path: &str) -> std::io::Result<()> {
+    match fs::remove_file(path).await {
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Remove all the files received from `chat_id`
+pub async fn clear_spool(chat_id: i64) -> std::io::Result<()> {
+    match fs::remove_dir_all(spool_dir(chat_id)).await {
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Remove `file` if it's older than `ttl`, returns whether it's removed
+fn sweep_file(file: &Path, ttl: Duration) -> std::io::Result<bool> {
+    let age = std::fs::metadata(file)?
+        .modified()?
+        .elapsed()
+        .unwrap_or_default();
+    if age <= ttl {
+        return Ok(false);
+    }
+    std::fs::remove_file(file)?;
+    Ok(true)
+}
+
+/// Remove files older than `ttl` from `spool`. Returns numbers of removed
+/// and left files.
+fn sweep_files(spool: &Path, ttl: Duration) -> std::io::Result<(usize, usize)> {
+    let (mut removed, mut left) = (0, 0);
+    for file in std::fs::read_dir(spool)? {
+        let file = match file {
+            Ok(file) => file.path(),
+            Err(e) => {
+                log::error!("Can't sweep {}: {}", spool.display(), e);
+                left += 1;
+                continue;
+            }
+        };
+        match sweep_file(&file, ttl) {
+            Ok(true) => removed += 1,
+            Ok(false) => left += 1,
+            // Handlers remove their files meanwhile
+            Err(e) if e.kind() == ErrorKind::NotFound => (),
+            Err(e) => {
+                log::error!("Can't sweep {}: {}", file.display(), e);
+                left += 1;
+            }
+        }
+    }
+    Ok((removed, left))
+}
+
+/// Remove files older than `ttl` from the spools under `root` along with
+/// the emptied spools. The files which can't be removed are logged and
+/// left. Returns number of removed files.
+fn sweep_dir(root: &Path, ttl: Duration) -> std::io::Result<usize> {
+    let mut removed = 0;
+    let spools = match std::fs::read_dir(root) {
+        Ok(spools) => spools,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    for spool in spools {
+        let spool = match spool {
+            Ok(spool) => spool.path(),
+            Err(e) => {
+                log::error!("Can't sweep {}: {}", root.display(), e);
+                continue;
+            }
+        };
+        if !spool.is_dir() {
+            continue;
+        }
+        let result = match sweep_files(&spool, ttl) {
+            Ok((swept, 0)) => {
+                removed += swept;
+                std::fs::remove_dir(&spool)
+            }
+            Ok((swept, _)) => {
+                removed += swept;
+                Ok(())
+            }
+            Err(e) => Err(e),
+        };
+        match result {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                log::error!("Can't sweep {}: {}", spool.display(), e)
+            }
+            _ => (),
+        }
+    }
+    Ok(removed)
+}
+
+/// Remove abandoned received files
+pub fn sweep_spool(ttl: Duration) -> std::io::Result<usize> {
+    sweep_dir(&spool_root(), ttl)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    enum Dialogue {
+        Idle,
+        File { filename: String },
+    }
+
+    #[tokio::test]
+    async fn test_file_storage() {
+        let dir = format!("{}dialogues_test", DEFAULT_DB_PATH);
+        let _ = std::fs::remove_dir_all(&dir);
+        let storage = FileStorage::<Dialogue>::new(&Some(dir.clone())).unwrap();
+        let chat = ChatId(42);
+
+        assert_eq!(storage.clone().get_dialogue(chat).await.unwrap(), None);
+        let state = Dialogue::File {
+            filename: "receipt.json".to_string(),
+        };
+        storage
+            .clone()
+            .update_dialogue(chat, state.clone())
+            .await
+            .unwrap();
+
+        #[cfg(unix)]
+        {
+            let mode = |p: &str| std::fs::metadata(p).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode(&dir), 0o700);
+            assert_eq!(mode(&format!("{}/42.json", dir)), 0o600);
+        }
+
+        // Dialogues survive restart
+        drop(storage);
+        let storage = FileStorage::<Dialogue>::new(&Some(dir.clone())).unwrap();
+        assert_eq!(
+            storage.clone().get_dialogue(chat).await.unwrap(),
+            Some(state)
+        );
+        storage.clone().remove_dialogue(chat).await.unwrap();
+        assert!(matches!(
+            storage.clone().remove_dialogue(chat).await,
+            Err(FileStorageError::DialogueNotFound)
+        ));
+
+        // Outdated state is dropped
+        std::fs::write(format!("{}/42.json", dir), r#"{"Removed":1}"#).unwrap();
+        assert_eq!(storage.clone().get_dialogue(chat).await.unwrap(), None);
+        storage.update_dialogue(chat, Dialogue::Idle).await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_sweep() {
+        let root = PathBuf::from(format!("{}spool_test", DEFAULT_DB_PATH));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("1")).unwrap();
+        std::fs::create_dir_all(root.join("2")).unwrap();
+        std::fs::write(root.join("1/receipt"), "{}").unwrap();
+
+        assert_eq!(sweep_dir(&root, Duration::from_secs(60)).unwrap(), 0);
+        assert!(root.join("1/receipt").exists());
+        assert!(!root.join("2").exists());
+
+        assert_eq!(sweep_dir(&root, Duration::ZERO).unwrap(), 1);
+        assert!(!root.join("1").exists());
+
+        // Entries which can't be removed don't stop the sweep
+        std::fs::create_dir_all(root.join("1/stray")).unwrap();
+        std::fs::create_dir_all(root.join("2")).unwrap();
+        std::fs::write(root.join("2/receipt"), "{}").unwrap();
+        assert_eq!(sweep_dir(&root, Duration::ZERO).unwrap(), 1);
+        assert!(root.join("1/stray").exists());
+        assert!(!root.join("2").exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_spool() {
+        let dir = create_spool(-7).unwrap();
+        let path = dir.join("receipt");
+        drop(create_spooled(&path).await.unwrap());
+        #[cfg(unix)]
+        {
+            let mode = |p: &Path| std::fs::metadata(p).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode(&dir), 0o700);
+            assert_eq!(mode(&path), 0o600);
+        }
+        remove_spooled(path.to_str().unwrap()).await.unwrap();
+        remove_spooled(path.to_str().unwrap()).await.unwrap();
+        clear_spool(-7).await.unwrap();
+        assert!(!dir.exists());
+    }
+}
@@ -1,52 +1,648 @@
-use crate::user::User;
-use tokio::sync::{mpsc, oneshot};
+use crate::accounts::AccountTree;
+use crate::filters::FilterConfig;
+use crate::knowledge::{Knowledge, MergeMode};
+use crate::user::{LoadError, User, UserError, DEFAULT_DB_PATH};
+use pickledb::{PickleDb, PickleDbDumpPolicy, SerializationMethod};
+use qif_generator::account::AccountType;
+use radix_trie::TrieCommon;
+use serde::{Deserialize, Serialize};
+use shellexpand::tilde;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tokio::sync::{mpsc, oneshot, Mutex};
+
+/// Database file with household memberships
+pub const HOUSEHOLDS_FILE: &str = "households.db";
+
+/// Invite codes not used for this long expire
+pub const INVITE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
 
 #[derive(Debug)]
 pub enum TgManagerCommand {
-    #[allow(dead_code)]
     Get {
         user_id: i64,
-        reply_to: oneshot::Sender<User>,
+        reply_to: oneshot::Sender<Result<SharedUser, LoadError>>,
+    },
+    /// Create an invite code to the household of `user_id`
+    Invite {
+        user_id: i64,
+        reply_to: oneshot::Sender<Result<String, HouseholdError>>,
+    },
+    /// Join the household by invite `code`, replies with the owner uid
+    Join {
+        user_id: i64,
+        code: String,
+        reply_to: oneshot::Sender<Result<i64, HouseholdError>>,
+    },
+    /// Return to the personal database
+    Leave {
+        user_id: i64,
+        reply_to: oneshot::Sender<Result<(), HouseholdError>>,
+    },
+    /// Apply the change to the database of `user_id`, replies with the
+    /// items it has moved
+    Update {
+        user_id: i64,
+        update: UserUpdate,
+        reply_to: oneshot::Sender<Result<Vec<String>, ManagerError>>,
+    },
+    /// Write the database of `user_id` to disk right away
+    Save {
+        user_id: i64,
+        reply_to: oneshot::Sender<Result<(), ManagerError>>,
+    },
+    /// Erase the personal database of `user_id`
+    Delete {
+        user_id: i64,
+        reply_to: oneshot::Sender<Result<(), ManagerError>>,
+    },
+    /// All users having a database, the ones not loaded stay unloaded.
+    /// There's no admin command to use it in the bot yet.
+    #[allow(dead_code)]
+    List {
+        reply_to: oneshot::Sender<Result<Vec<UserSummary>, ManagerError>>,
     },
 }
 
-#[derive(Debug)]
-pub enum TgUserManagerError {
-    SendError,
+/// Change of the user data done by the manager
+#[derive(Debug, Clone)]
+pub enum UserUpdate {
+    /// Count `category` for the normalized `item`
+    AssignCategory {
+        item: String,
+        category: String,
+    },
+    AddAccount(String),
+    /// Default account the purchases are paid from
+    PaymentAccount {
+        name: String,
+        account_type: Option<AccountType>,
+    },
+    SetSetting {
+        key: String,
+        value: String,
+    },
+    /// Merge categories learned by someone else
+    ImportKnowledge {
+        knowledge: Knowledge,
+        mode: MergeMode,
+    },
+    /// Replace the accounts with the imported ones
+    AccountTree(AccountTree),
+    /// Move the items of `from` and its subcategories to `to`
+    RenameCategory {
+        from: String,
+        to: String,
+    },
+    /// Remember the converted receipts by their ids
+    MarkProcessed(Vec<String>),
+}
+
+impl UserUpdate {
+    /// Apply the change requested by `member` of the household `user` is
+    /// shared with, the settings are kept per member. Returns the items
+    /// moved to another category, only renaming moves them.
+    fn apply(self, user: &mut User, member: i64) -> Result<Vec<String>, UserError> {
+        match self {
+            UserUpdate::AssignCategory { item, category } => {
+                user.assign_category(&item, &category)?
+            }
+            UserUpdate::AddAccount(account) => user.new_account(account),
+            UserUpdate::SetSetting { key, value } => {
+                user.set_member_setting(member, &key, &value)?
+            }
+            UserUpdate::ImportKnowledge { knowledge, mode } => {
+                user.import_knowledge(&knowledge, mode)?;
+            }
+            UserUpdate::AccountTree(tree) => {
+                user.account_tree(tree);
+                user.save_data()?
+            }
+            UserUpdate::RenameCategory { from, to } => return user.rename_category(&from, &to),
+            UserUpdate::MarkProcessed(ids) => {
+                for id in ids {
+                    user.mark_processed(&id)?
+                }
+            }
+            UserUpdate::PaymentAccount { name, account_type } => {
+                user.set_payment_account(member, &name, account_type)?
+            }
+        }
+        Ok(vec![])
+    }
+}
+
+/// Short statistics of the user database
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserSummary {
+    pub uid: i64,
+    /// Number of items with learned categories
+    pub items: usize,
+    pub accounts: usize,
+}
+
+#[derive(Debug, Error)]
+pub enum ManagerError {
+    #[error(transparent)]
+    Load(#[from] LoadError),
+    #[error(transparent)]
+    User(#[from] UserError),
+    #[error(transparent)]
+    Household(#[from] HouseholdError),
+    #[error("The data is in use, try again later")]
+    Busy,
+    #[error("Can't list users: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Send the reply, the requester may be gone already
+fn reply<T>(reply_to: oneshot::Sender<T>, value: T) {
+    if reply_to.send(value).is_err() {
+        log::warn!("Requester left before the reply");
+    }
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum HouseholdError {
+    #[error("Invite code is unknown, expired or already used")]
+    UnknownInvite,
+    #[error("Already a member of this household")]
+    AlreadyMember,
+    #[error("Other users share your categories, they have to leave first")]
+    HasMembers,
+    #[error("Not a member of any household")]
+    NotMember,
+    #[error("Database error: {0}")]
+    Db(String),
+    #[error("Can't load households from {0}: {1}")]
+    Load(String, String),
+}
+
+impl From<pickledb::error::Error> for HouseholdError {
+    fn from(e: pickledb::error::Error) -> Self {
+        HouseholdError::Db(e.to_string())
+    }
+}
+
+/// Loaded user shared between the handlers
+pub type SharedUser = Arc<Mutex<User>>;
+
+/// Users not requested for this long are saved and unloaded
+const IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// How often the cached users are saved and the idle ones unloaded
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+struct CachedUser {
+    user: SharedUser,
+    last_used: Instant,
+}
+
+/// Loaded users by database owner uid
+#[derive(Default)]
+pub struct UserCache {
+    users: HashMap<i64, CachedUser>,
+    /// Filters the older databases are migrated with
+    filters: FilterConfig,
+}
+
+impl UserCache {
+    pub fn new(filters: FilterConfig) -> Self {
+        UserCache {
+            users: HashMap::new(),
+            filters,
+        }
+    }
+
+    /// Cached user or the one loaded from disk
+    pub fn get(&mut self, uid: i64) -> Result<SharedUser, LoadError> {
+        if let Some(cached) = self.users.get_mut(&uid) {
+            cached.last_used = Instant::now();
+            return Ok(cached.user.clone());
+        }
+        let user = Arc::new(Mutex::new(User::new(uid, &None, &self.filters)?));
+        self.users.insert(
+            uid,
+            CachedUser {
+                user: user.clone(),
+                last_used: Instant::now(),
+            },
+        );
+        Ok(user)
+    }
+
+    /// Cached user if it's loaded, the idle time is not reset
+    fn peek(&self, uid: i64) -> Option<SharedUser> {
+        self.users.get(&uid).map(|cached| cached.user.clone())
+    }
+
+    /// Save the users which are not in use right now
+    pub fn flush(&self) {
+        for (uid, cached) in &self.users {
+            if let Ok(mut user) = cached.user.try_lock() {
+                user.save_data().unwrap_or_else(|err| {
+                    log::error!("Can't save database for uid {} due to {:?}", uid, err)
+                });
+            }
+        }
+    }
+
+    /// Unload the users idle for longer than `timeout` and not held by any
+    /// handler, they are saved on drop. Returns number of unloaded users.
+    pub fn evict(&mut self, timeout: Duration) -> usize {
+        let before = self.users.len();
+        self.users.retain(|_, cached| {
+            cached.last_used.elapsed() < timeout || Arc::strong_count(&cached.user) > 1
+        });
+        before - self.users.len()
+    }
+
+    /// Take the user out of the cache unless some handler holds it
+    pub fn unload(&mut self, uid: i64) -> Result<Option<User>, ManagerError> {
+        match self.users.get(&uid) {
+            None => return Ok(None),
+            Some(cached) if Arc::strong_count(&cached.user) > 1 => return Err(ManagerError::Busy),
+            Some(_) => (),
+        }
+        let cached = self.users.remove(&uid).expect("checked above");
+        match Arc::try_unwrap(cached.user) {
+            Ok(user) => Ok(Some(user.into_inner())),
+            Err(_) => unreachable!("the user is not shared"),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+}
+
+/// Uids of the users having a database in the default location
+fn known_users() -> Result<Vec<i64>, std::io::Error> {
+    let dir = PathBuf::from(tilde(DEFAULT_DB_PATH).as_ref());
+    let mut uids = vec![];
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if let Some(uid) = name.strip_suffix(".db").and_then(|n| n.parse().ok()) {
+            uids.push(uid);
+        }
+    }
+    uids.sort();
+    Ok(uids)
+}
+
+/// Erase the personal database of `uid` and its household membership.
+/// Settings of a member are removed from the shared database too, while the
+/// receipts they converted stay there as the household history.
+fn delete_user(
+    cache: &mut UserCache,
+    households: &mut Households,
+    uid: i64,
+) -> Result<(), ManagerError> {
+    if !households.members(uid).is_empty() {
+        return Err(HouseholdError::HasMembers.into());
+    }
+    let owner = households.owner(uid);
+    if owner != uid {
+        let shared = cache.get(owner)?;
+        shared
+            .try_lock()
+            .map_err(|_| ManagerError::Busy)?
+            .remove_member_settings(uid)?;
+    }
+    let user = match cache.unload(uid)? {
+        Some(user) => user,
+        None => match User::load(uid, &None, &cache.filters) {
+            Ok(user) => user,
+            Err(LoadError::NotFound(_)) => return leave_household(households, uid),
+            Err(e) => return Err(e.into()),
+        },
+    };
+    user.erase()?;
+    leave_household(households, uid)
+}
+
+fn leave_household(households: &mut Households, uid: i64) -> Result<(), ManagerError> {
+    match households.leave(uid) {
+        Ok(()) | Err(HouseholdError::NotMember) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Groups of users sharing a single categories database
+///
+/// Every member is mapped to the household owner, whose database holds the
+/// shared statistics and accounts.
+pub struct Households {
+    db: PickleDb,
+}
+
+/// Stored invite to the household of `owner`
+#[derive(Serialize, Deserialize)]
+struct Invite {
+    owner: i64,
+    /// Expiration time in seconds since the Unix epoch
+    expires: u64,
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl Households {
+    /// Load the memberships, the file is only created if there's none.
+    /// Unreadable file is reported rather than replaced with empty one.
+    pub fn new(dbfile: &Option<String>) -> Result<Self, HouseholdError> {
+        let path = match dbfile {
+            Some(path) => path.to_string(),
+            None => DEFAULT_DB_PATH.to_owned() + HOUSEHOLDS_FILE,
+        };
+        let path = PathBuf::from(tilde(&path).as_ref());
+        let db = if path.exists() {
+            PickleDb::load(
+                &path,
+                PickleDbDumpPolicy::AutoDump,
+                SerializationMethod::Json,
+            )
+            .map_err(|e| HouseholdError::Load(path.display().to_string(), e.to_string()))?
+        } else {
+            PickleDb::new(
+                &path,
+                PickleDbDumpPolicy::AutoDump,
+                SerializationMethod::Json,
+            )
+        };
+        Ok(Households { db })
+    }
+
+    fn member_key(uid: i64) -> String {
+        format!("member:{}", uid)
+    }
+
+    fn invite_key(code: &str) -> String {
+        format!("invite:{}", code.trim().to_lowercase())
+    }
+
+    /// Uid which database is used for `uid`
+    pub fn owner(&self, uid: i64) -> i64 {
+        self.db.get(&Households::member_key(uid)).unwrap_or(uid)
+    }
+
+    /// Users sharing the database of `owner`, not including the owner
+    pub fn members(&self, owner: i64) -> Vec<i64> {
+        self.db
+            .get_all()
+            .iter()
+            .filter_map(|key| key.strip_prefix("member:"))
+            .filter_map(|uid| uid.parse().ok())
+            .filter(|uid| self.owner(*uid) == owner)
+            .collect()
+    }
+
+    /// Create a single-use invite code to the household of `uid` valid
+    /// for `INVITE_TTL`
+    pub fn invite(&mut self, uid: i64) -> Result<String, HouseholdError> {
+        self.invite_for(uid, INVITE_TTL)
+    }
+
+    fn invite_for(&mut self, uid: i64, ttl: Duration) -> Result<String, HouseholdError> {
+        self.remove_expired()?;
+        let invite = Invite {
+            owner: self.owner(uid),
+            expires: unix_time() + ttl.as_secs(),
+        };
+        let code = format!("{:032x}", rand::random::<u128>());
+        self.db.set(&Households::invite_key(&code), &invite)?;
+        Ok(code)
+    }
+
+    /// Drop the invites nobody used in time
+    fn remove_expired(&mut self) -> Result<(), HouseholdError> {
+        let now = unix_time();
+        let expired: Vec<String> = self
+            .db
+            .get_all()
+            .into_iter()
+            .filter(|key| key.starts_with("invite:"))
+            .filter(|key| {
+                self.db
+                    .get::<Invite>(key)
+                    .is_none_or(|invite| invite.expires <= now)
+            })
+            .collect();
+        for key in expired {
+            self.db.rem(&key)?;
+        }
+        Ok(())
+    }
+
+    /// Make `uid` a member of the household `code` invites to
+    pub fn join(&mut self, uid: i64, code: &str) -> Result<i64, HouseholdError> {
+        let key = Households::invite_key(code);
+        let invite: Invite = self.db.get(&key).ok_or(HouseholdError::UnknownInvite)?;
+        if invite.expires <= unix_time() {
+            self.db.rem(&key)?;
+            return Err(HouseholdError::UnknownInvite);
+        }
+        let owner = invite.owner;
+        if self.owner(uid) == owner {
+            return Err(HouseholdError::AlreadyMember);
+        }
+        if !self.members(uid).is_empty() {
+            return Err(HouseholdError::HasMembers);
+        }
+        self.db.rem(&key)?;
+        self.db.set(&Households::member_key(uid), &owner)?;
+        Ok(owner)
+    }
+
+    /// Return `uid` to the personal database
+    pub fn leave(&mut self, uid: i64) -> Result<(), HouseholdError> {
+        if self.db.rem(&Households::member_key(uid))? {
+            Ok(())
+        } else {
+            Err(HouseholdError::NotMember)
+        }
+    }
 }
 
 pub async fn user_manager(
     rx: &mut mpsc::Receiver<TgManagerCommand>,
-) -> Result<(), TgUserManagerError> {
+    mut households: Households,
+    filters: FilterConfig,
+) {
     log::info!("Request came");
-    while let Some(cmd) = rx.recv().await {
+    let mut cache = UserCache::new(filters);
+    let mut ticker = tokio::time::interval(FLUSH_INTERVAL);
+    loop {
         use TgManagerCommand::*;
+        let cmd = tokio::select! {
+            cmd = rx.recv() => match cmd {
+                Some(cmd) => cmd,
+                None => break,
+            },
+            _ = ticker.tick() => {
+                cache.flush();
+                let evicted = cache.evict(IDLE_TIMEOUT);
+                if evicted > 0 {
+                    log::info!("Unloaded {} idle users", evicted);
+                }
+                continue;
+            }
+        };
         log::info!("Command received");
 
         match cmd {
             Get { user_id, reply_to } => {
                 log::info!("{}", format!("Get command found, sending {}", user_id));
-                reply_to
-                    .send(User::new(user_id, &None))
-                    .map_err(|_| TgUserManagerError::SendError)?
+                reply(reply_to, cache.get(households.owner(user_id)))
+            }
+            Invite { user_id, reply_to } => reply(reply_to, households.invite(user_id)),
+            Join {
+                user_id,
+                code,
+                reply_to,
+            } => reply(reply_to, households.join(user_id, &code)),
+            Leave { user_id, reply_to } => reply(reply_to, households.leave(user_id)),
+            Update {
+                user_id,
+                update,
+                reply_to,
+            } => match cache.get(households.owner(user_id)) {
+                // Handlers may hold the user for a while, the manager
+                // shouldn't wait for them
+                Ok(user) => {
+                    tokio::spawn(async move {
+                        let result = update.apply(&mut *user.lock().await, user_id);
+                        reply(reply_to, result.map_err(ManagerError::from))
+                    });
+                }
+                Err(e) => reply(reply_to, Err(e.into())),
+            },
+            Save { user_id, reply_to } => match cache.get(households.owner(user_id)) {
+                Ok(user) => {
+                    tokio::spawn(async move {
+                        let result = user.lock().await.save_data();
+                        reply(reply_to, result.map_err(ManagerError::from))
+                    });
+                }
+                Err(e) => reply(reply_to, Err(e.into())),
+            },
+            Delete { user_id, reply_to } => {
+                reply(reply_to, delete_user(&mut cache, &mut households, user_id))
             }
+            List { reply_to } => match known_users() {
+                Ok(uids) => {
+                    // Databases not in use are only peeked at, loading them
+                    // would migrate or quarantine them
+                    let users: Vec<(i64, Option<SharedUser>)> =
+                        uids.into_iter().map(|uid| (uid, cache.peek(uid))).collect();
+                    tokio::spawn(async move {
+                        let mut summaries = vec![];
+                        for (uid, user) in users {
+                            let stats = match user {
+                                Some(user) => {
+                                    let user = user.lock().await;
+                                    Ok((user.catmap.len(), user.accounts.len()))
+                                }
+                                None => User::stored_stats(uid, &None),
+                            };
+                            match stats {
+                                Ok((items, accounts)) => summaries.push(UserSummary {
+                                    uid,
+                                    items,
+                                    accounts,
+                                }),
+                                Err(e) => log::warn!("Can't read stats of {}: {}", uid, e),
+                            }
+                        }
+                        reply(reply_to, Ok(summaries))
+                    });
+                }
+                Err(e) => reply(reply_to, Err(e.into())),
+            },
         }
     }
-    Ok(())
+    log::info!("Saving {} cached users", cache.len());
+    cache.flush();
 }
 
 #[cfg(test)]
 mod tgusertest {
     use super::*;
+    use crate::accounts::{AccountInfo, AccountKind};
+    use std::fs::{self, create_dir_all, remove_file};
     use tokio::sync::oneshot;
 
+    #[test]
+    fn households() {
+        create_dir_all(DEFAULT_DB_PATH).unwrap();
+        let path = format!("{}households_test.db", DEFAULT_DB_PATH);
+        let _ = remove_file(&path);
+        let mut hh = Households::new(&Some(path.clone())).unwrap();
+
+        let code = hh.invite(1).unwrap();
+        assert_eq!(hh.join(1, &code), Err(HouseholdError::AlreadyMember));
+        assert_eq!(hh.join(2, &code), Ok(1));
+        assert_eq!(hh.join(3, &code), Err(HouseholdError::UnknownInvite));
+        assert_eq!(hh.owner(2), 1);
+        assert_eq!(hh.members(1), vec![2]);
+
+        // Invites by members lead to the owner
+        let code = hh.invite(2).unwrap();
+        assert_eq!(hh.join(3, &code.to_uppercase()), Ok(1));
+        assert_eq!(hh.owner(3), 1);
+
+        let code = hh.invite(4).unwrap();
+        assert_eq!(hh.join(1, &code), Err(HouseholdError::HasMembers));
+
+        assert_eq!(hh.leave(2), Ok(()));
+        assert_eq!(hh.owner(2), 2);
+        assert_eq!(hh.leave(2), Err(HouseholdError::NotMember));
+
+        // Memberships survive restart
+        drop(hh);
+        let mut hh = Households::new(&Some(path.clone())).unwrap();
+        assert_eq!(hh.owner(3), 1);
+
+        // Codes are random, expire and are not reused
+        let code = hh.invite(1).unwrap();
+        assert_eq!(code.len(), 32);
+        assert_ne!(hh.invite(1).unwrap(), code);
+        let expired = hh.invite_for(1, Duration::ZERO).unwrap();
+        assert_eq!(hh.join(5, &expired), Err(HouseholdError::UnknownInvite));
+        assert_eq!(hh.join(5, &code), Ok(1));
+        assert_eq!(hh.join(6, &code), Err(HouseholdError::UnknownInvite));
+        drop(hh);
+
+        // Unreadable memberships are not replaced
+        fs::write(&path, "{broken").unwrap();
+        assert!(matches!(
+            Households::new(&Some(path.clone())),
+            Err(HouseholdError::Load(..))
+        ));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "{broken");
+        remove_file(&path).unwrap();
+    }
+
     #[tokio::test]
     async fn manager() {
         let (tx, mut rx) = mpsc::channel(32);
         let (response_tx, response_rx) = oneshot::channel();
 
         tokio::spawn(async move {
-            user_manager(&mut rx).await.unwrap();
+            user_manager(
+                &mut rx,
+                Households::new(&None).unwrap(),
+                FilterConfig::default(),
+            )
+            .await;
         });
 
         tx.send(TgManagerCommand::Get {
@@ -63,4 +659,155 @@ mod tgusertest {
             println!("The sender dropped without sending a response");
         }
     }
+
+    #[tokio::test]
+    async fn cache() {
+        create_dir_all(DEFAULT_DB_PATH).unwrap();
+        let mut cache = UserCache::default();
+        let first = cache.get(-42).unwrap();
+        let second = cache.get(-42).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+
+        // Users held by handlers are kept
+        first.lock().await.new_account("Expenses:Cache".to_string());
+        assert_eq!(cache.evict(Duration::ZERO), 0);
+        drop(first);
+        drop(second);
+        assert_eq!(cache.evict(Duration::from_secs(60)), 0);
+        assert_eq!(cache.evict(Duration::ZERO), 1);
+        assert_eq!(cache.len(), 0);
+
+        // Evicted user is saved on drop
+        let user = cache.get(-42).unwrap();
+        assert!(user.lock().await.accounts.contains("Expenses:Cache"));
+        drop(user);
+        drop(cache);
+        remove_file(format!("{}-42.db", DEFAULT_DB_PATH)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn commands() {
+        create_dir_all(DEFAULT_DB_PATH).unwrap();
+        let (tx, mut rx) = mpsc::channel(32);
+        let manager = tokio::spawn(async move {
+            user_manager(
+                &mut rx,
+                Households::new(&None).unwrap(),
+                FilterConfig::default(),
+            )
+            .await
+        });
+        let uid = -43;
+
+        let (reply_to, response) = oneshot::channel();
+        tx.send(TgManagerCommand::Update {
+            user_id: uid,
+            update: UserUpdate::AssignCategory {
+                item: "milk".to_string(),
+                category: "Expenses:Dairy".to_string(),
+            },
+            reply_to,
+        })
+        .await
+        .unwrap();
+        response.await.unwrap().unwrap();
+
+        let (reply_to, response) = oneshot::channel();
+        tx.send(TgManagerCommand::Get {
+            user_id: uid,
+            reply_to,
+        })
+        .await
+        .unwrap();
+        let user = response.await.unwrap().unwrap();
+
+        // Handler holding the user blocks deletion
+        let (reply_to, response) = oneshot::channel();
+        tx.send(TgManagerCommand::Delete {
+            user_id: uid,
+            reply_to,
+        })
+        .await
+        .unwrap();
+        assert!(matches!(response.await.unwrap(), Err(ManagerError::Busy)));
+        drop(user);
+
+        let (reply_to, response) = oneshot::channel();
+        tx.send(TgManagerCommand::Save {
+            user_id: uid,
+            reply_to,
+        })
+        .await
+        .unwrap();
+        response.await.unwrap().unwrap();
+
+        let (reply_to, response) = oneshot::channel();
+        tx.send(TgManagerCommand::Update {
+            user_id: uid,
+            update: UserUpdate::AccountTree(
+                [AccountInfo::new(AccountKind::Expense, "Expenses:Dairy")]
+                    .into_iter()
+                    .collect(),
+            ),
+            reply_to,
+        })
+        .await
+        .unwrap();
+        response.await.unwrap().unwrap();
+
+        let (reply_to, response) = oneshot::channel();
+        tx.send(TgManagerCommand::List { reply_to }).await.unwrap();
+        let summary = response.await.unwrap().unwrap();
+        assert!(summary.contains(&UserSummary {
+            uid,
+            items: 1,
+            accounts: 1,
+        }));
+
+        let (reply_to, response) = oneshot::channel();
+        tx.send(TgManagerCommand::Update {
+            user_id: uid,
+            update: UserUpdate::RenameCategory {
+                from: "Expenses:Dairy".to_string(),
+                to: "Expenses:Food:Dairy".to_string(),
+            },
+            reply_to,
+        })
+        .await
+        .unwrap();
+        assert_eq!(response.await.unwrap().unwrap(), vec!["milk".to_string()]);
+
+        let (reply_to, response) = oneshot::channel();
+        tx.send(TgManagerCommand::Update {
+            user_id: uid,
+            update: UserUpdate::MarkProcessed(vec!["fn:1:2".to_string()]),
+            reply_to,
+        })
+        .await
+        .unwrap();
+        assert!(response.await.unwrap().unwrap().is_empty());
+        let (reply_to, response) = oneshot::channel();
+        tx.send(TgManagerCommand::Get {
+            user_id: uid,
+            reply_to,
+        })
+        .await
+        .unwrap();
+        let user = response.await.unwrap().unwrap();
+        assert!(user.lock().await.is_processed("fn:1:2"));
+        drop(user);
+
+        let (reply_to, response) = oneshot::channel();
+        tx.send(TgManagerCommand::Delete {
+            user_id: uid,
+            reply_to,
+        })
+        .await
+        .unwrap();
+        response.await.unwrap().unwrap();
+        assert!(!PathBuf::from(format!("{}{}.db", DEFAULT_DB_PATH, uid)).exists());
+
+        drop(tx);
+        manager.await.unwrap();
+    }
 }
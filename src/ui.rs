@@ -1,3 +1,4 @@
+use crate::accounts::{children, Level};
 use rustyline::completion::Completer;
 use rustyline::config::OutputStreamType;
 use rustyline::error::ReadlineError;
@@ -30,6 +31,29 @@ pub fn run_tv() {
     println!("Hello, world!");
 }
 
+/// Drill down the account hierarchy while `line` is a prefix of some
+/// categories, otherwise offer all the categories containing it
+fn complete_category(cats: &[&String], line: &str) -> Vec<String> {
+    let parent = line.rsplit_once(':').map(|(p, _)| p).unwrap_or_default();
+    let results: Vec<String> = children(cats.iter().copied(), parent)
+        .into_iter()
+        .map(|level| match level {
+            Level::Group(group) => group + ":",
+            Level::Leaf(leaf) => leaf,
+        })
+        .filter(|comp| comp.starts_with(line))
+        .collect();
+
+    if !results.is_empty() {
+        return results;
+    }
+
+    cats.iter()
+        .filter(|comp| comp.contains(line))
+        .map(|s| s.to_string())
+        .collect()
+}
+
 struct CatCompleter<'a> {
     completions: &'a [&'a String],
 }
@@ -43,14 +67,7 @@ impl Completer for CatCompleter<'_> {
         _pos: usize,
         _ctx: &Context<'_>,
     ) -> rustyline::Result<(usize, Vec<Self::Candidate>)> {
-        let results: Vec<String> = self
-            .completions
-            .iter()
-            .filter(|comp| comp.contains(line))
-            .map(|s| s.to_string())
-            .collect();
-
-        Ok((0, results))
+        Ok((0, complete_category(self.completions, line)))
     }
 
     fn update(&self, _line: &mut LineBuffer, _start: usize, _elected: &str) {}
@@ -180,3 +197,31 @@ pub fn input_category(item: &str, cat: &str, cats: &[&String]) -> String {
     print!("\x1b[1;0m");
     String::from(result.trim_end_matches('\n'))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_complete_category() {
+        let cats = [
+            "Expenses:Food:Bread".to_string(),
+            "Expenses:Food:Dairy".to_string(),
+            "Expenses:Transport".to_string(),
+        ];
+        let cats: Vec<&String> = cats.iter().collect();
+        assert_eq!(
+            complete_category(&cats, "Expenses:"),
+            vec!["Expenses:Food:", "Expenses:Transport"]
+        );
+        assert_eq!(
+            complete_category(&cats, "Expenses:Food:D"),
+            vec!["Expenses:Food:Dairy"]
+        );
+        assert_eq!(
+            complete_category(&cats, "Bread"),
+            vec!["Expenses:Food:Bread"]
+        );
+        assert!(complete_category(&cats, "Salary").is_empty());
+    }
+}
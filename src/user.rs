@@ -1,12 +1,20 @@
-use crate::categories::CatStats;
+use crate::accounts::{AccountInfo, AccountKind, AccountTree};
+use crate::categories::{self, CatStats};
+use crate::filters::FilterConfig;
+use crate::knowledge::{Knowledge, MergeMode};
+use crate::store::{self, StoreError, UserStore};
 use derive_more::From;
-use pickledb::{PickleDb, PickleDbDumpPolicy, SerializationMethod};
+use qif_generator::account::{Account, AccountType};
+#[cfg(test)]
 use radix_trie::Trie;
+use radix_trie::TrieCommon;
 use shellexpand::tilde;
+use std::borrow::Cow;
 use std::collections::HashSet;
 use std::fmt;
-use std::path::PathBuf;
-use std::time::Duration;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
 use thiserror::Error;
 
 /// Configuration for single user
@@ -20,8 +28,17 @@ pub struct User {
     /// Available accounts for the user
     pub accounts: HashSet<String>,
 
+    /// Imported accounts with types and flags
+    pub account_tree: AccountTree,
+
     /// database with config
-    db: PickleDb,
+    db: Box<dyn UserStore>,
+
+    /// Location of the database
+    path: PathBuf,
+
+    /// The data is erased and must not be saved back
+    erased: bool,
 }
 
 #[cfg(all(not(test), not(feature = "docker")))]
@@ -31,16 +48,122 @@ pub const DEFAULT_DB_PATH: &str = "~/.config/receqif/";
 pub const DEFAULT_DB_PATH: &str = "/etc/receqif/";
 
 #[cfg(test)]
-const DEFAULT_DB_PATH: &str = "/tmp/receqif_test/";
+pub const DEFAULT_DB_PATH: &str = "/tmp/receqif_test/";
+
+/// Setting with the account the purchases are paid from
+const ACCOUNT_SETTING: &str = "account";
+
+/// Setting with the QIF type of the payment account
+const ACCOUNT_TYPE_SETTING: &str = "account_type";
+
+/// Setting with the memo used when none is given
+pub const MEMO_SETTING: &str = "memo";
+
+/// Preferences for the generated QIF
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    /// Default account the purchases are paid from
+    pub account: Option<String>,
+    pub account_type: AccountType,
+    pub memo: String,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            account: None,
+            account_type: AccountType::Bank,
+            memo: "purchase".to_string(),
+        }
+    }
+}
+
+/// Name of the QIF type accepted by its `FromStr`
+fn account_type_name(account_type: AccountType) -> &'static str {
+    match account_type {
+        AccountType::Bank => "Bank",
+        AccountType::Cash => "Cash",
+        AccountType::CreditCard => "CreditCard",
+        AccountType::Investment => "Investment",
+        AccountType::AssetAccount => "AssetAccount",
+        AccountType::LiabilityAccount => "LiabilityAccount",
+    }
+}
 
 #[derive(Debug, Error, From)]
 pub enum UserError {
     #[error("Database error: {0}")]
-    DbError(#[source] pickledb::error::Error),
+    DbError(#[source] StoreError),
+}
+
+/// Why the user database couldn't be loaded
+#[derive(Debug, Error)]
+pub enum LoadError {
+    #[error("Database {} not found", .0.display())]
+    NotFound(PathBuf),
+    #[error(
+        "Database {} is corrupt, moved to {}: {source}",
+        path.display(),
+        quarantine.display()
+    )]
+    Corrupt {
+        path: PathBuf,
+        quarantine: PathBuf,
+        #[source]
+        source: StoreError,
+    },
+    #[error("Can't read database {}: {source}", path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: StoreError,
+    },
+}
+
+/// Move corrupt database out of the way, so it can be recovered later
+fn quarantine(path: &Path) -> std::io::Result<PathBuf> {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(
+        ".corrupt-{}",
+        chrono::Local::now().format("%Y%m%d%H%M%S")
+    ));
+    let target = PathBuf::from(name);
+    fs::rename(path, &target)?;
+    Ok(target)
+}
+
+impl LoadError {
+    /// Corrupt database at `path` is quarantined, other errors are passed as is
+    fn from_store(path: PathBuf, source: StoreError) -> Self {
+        if !source.is_corrupt() {
+            return LoadError::Io { path, source };
+        }
+        match quarantine(&path) {
+            Ok(quarantine) => {
+                log::error!(
+                    "Database {} is corrupt, moved to {}",
+                    path.display(),
+                    quarantine.display()
+                );
+                LoadError::Corrupt {
+                    path,
+                    quarantine,
+                    source,
+                }
+            }
+            Err(e) => LoadError::Io {
+                path,
+                source: StoreError::Io(e),
+            },
+        }
+    }
 }
 
 impl Drop for User {
     fn drop(&mut self) {
+        if self.erased {
+            return;
+        }
         self.save_data().unwrap_or_else(|err| {
             log::error!("Can't save database for uid {} due to {:?}", self.uid, err)
         });
@@ -51,88 +174,343 @@ impl fmt::Debug for User {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("User")
             .field("uid", &self.uid)
-            .field("db", &format_args!("<PickleDb>"))
+            .field("db", &format_args!("<UserStore>"))
             .finish()
     }
 }
 
 impl User {
-    pub fn new(uid: i64, dbfile: &Option<String>) -> Self {
-        let ten_sec = Duration::from_secs(10);
+    fn db_path(uid: i64, dbfile: &Option<String>) -> PathBuf {
         let path: String = match dbfile {
             Some(path) => path.to_string(),
             None => DEFAULT_DB_PATH.to_owned() + &uid.to_string() + ".db",
         };
         let confpath: &str = &tilde(&path);
-        let confpath = PathBuf::from(confpath);
+        PathBuf::from(confpath)
+    }
 
-        let dbase = PickleDb::load(
-            &confpath,
-            PickleDbDumpPolicy::PeriodicDump(ten_sec),
-            SerializationMethod::Json,
-        );
+    /// Load the user from existing database, older databases are migrated
+    /// with `filters`
+    pub fn load(
+        uid: i64,
+        dbfile: &Option<String>,
+        filters: &FilterConfig,
+    ) -> Result<Self, LoadError> {
+        let confpath = User::db_path(uid, dbfile);
+        match fs::metadata(&confpath) {
+            Ok(_) => User::open(uid, confpath, filters),
+            Err(e) if e.kind() == ErrorKind::NotFound => Err(LoadError::NotFound(confpath)),
+            Err(e) => Err(LoadError::Io {
+                path: confpath,
+                source: StoreError::Io(e),
+            }),
+        }
+    }
 
-        let db = match dbase {
-            Ok(db) => db,
-            Err(_) => PickleDb::new(
-                &confpath,
-                PickleDbDumpPolicy::PeriodicDump(ten_sec),
-                SerializationMethod::Json,
-            ),
-        };
+    /// Numbers of learned items and categories stored for `uid`, the
+    /// database is read as is without loading the user
+    pub fn stored_stats(uid: i64, dbfile: &Option<String>) -> Result<(usize, usize), LoadError> {
+        let path = User::db_path(uid, dbfile);
+        let stats = store::open_read_only(&path)
+            .and_then(|db| Ok((db.load_catmap()?.len(), db.load_accounts()?.0.len())));
+        stats.map_err(|source| LoadError::Io { path, source })
+    }
 
-        let catmap: CatStats = match db.get("catmap") {
-            Some(v) => v,
-            None => Trie::new(),
-        };
+    /// Load the user or start a new database if there's none
+    pub fn new(
+        uid: i64,
+        dbfile: &Option<String>,
+        filters: &FilterConfig,
+    ) -> Result<Self, LoadError> {
+        match User::load(uid, dbfile, filters) {
+            Err(LoadError::NotFound(path)) => User::open(uid, path, filters),
+            result => result,
+        }
+    }
 
-        let accounts = match db.get::<Vec<String>>("accounts") {
-            Some(a) => HashSet::from_iter(a),
-            None => HashSet::new(),
+    fn open(uid: i64, confpath: PathBuf, filters: &FilterConfig) -> Result<Self, LoadError> {
+        let db = match store::open(&confpath, filters) {
+            Ok(db) => db,
+            Err(e) => return Err(LoadError::from_store(confpath, e)),
         };
-
-        User {
-            uid,
-            catmap,
-            accounts,
-            db,
+        let loaded = db
+            .load_catmap()
+            .and_then(|catmap| Ok((catmap, db.load_accounts()?)));
+        match loaded {
+            Ok((catmap, (accounts, account_tree))) => Ok(User {
+                uid,
+                catmap,
+                accounts,
+                account_tree,
+                db,
+                path: confpath,
+                erased: false,
+            }),
+            Err(e) => {
+                // The store must be closed before the file is moved
+                drop(db);
+                Err(LoadError::from_store(confpath, e))
+            }
         }
     }
 
-    pub fn accounts(&mut self, acc: Vec<String>) {
-        self.accounts = HashSet::from_iter(acc);
+    /// Replace the accounts with imported `tree`, categories are taken from
+    /// its postable expense accounts
+    pub fn account_tree(&mut self, tree: AccountTree) {
+        self.accounts = tree
+            .categories()
+            .filter(|a| a.is_postable())
+            .map(|a| a.name.clone())
+            .collect();
+        self.account_tree = tree;
     }
 
+    /// Save the accounts. Learned categories are written when they change,
+    /// so the statistics stored by other sessions are kept.
     pub fn save_data(&mut self) -> Result<(), UserError> {
         log::debug!("Saving user data");
         self.db
-            .set("catmap", &self.catmap)
+            .save_accounts(&self.accounts, &self.account_tree)
             .map_err(UserError::DbError)?;
 
+        self.db.flush().map_err(UserError::DbError)?;
+
+        Ok(())
+    }
+
+    /// Learn `category` for `item` and store it right away
+    pub fn assign_category(&mut self, item: &str, category: &str) -> Result<(), UserError> {
+        categories::assign_category(item, category, &mut self.catmap);
+        self.db
+            .add_hits(item, category, 1)
+            .map_err(UserError::DbError)
+    }
+
+    /// Replace the stored statistics after a bulk change
+    fn save_catmap(&mut self) -> Result<(), UserError> {
         self.db
-            .set("accounts", &self.accounts)
+            .save_catmap(&self.catmap)
             .map_err(UserError::DbError)?;
+        self.db.flush().map_err(UserError::DbError)
+    }
 
-        self.db.dump().map_err(UserError::DbError)?;
+    /// Rename or merge category `from` into `to`, returns the moved items
+    pub fn rename_category(&mut self, from: &str, to: &str) -> Result<Vec<String>, UserError> {
+        let affected = categories::rename_category(from, to, &mut self.catmap);
+        if !affected.is_empty() {
+            self.save_catmap()?;
+        }
+        Ok(affected)
+    }
 
+    /// Move the hits of exactly `from` to `to` leaving its subcategories,
+    /// returns the moved items
+    pub fn remap_category(&mut self, from: &str, to: &str) -> Result<Vec<String>, UserError> {
+        let affected = categories::remap_category(from, to, &mut self.catmap);
+        if !affected.is_empty() {
+            self.save_catmap()?;
+        }
+        Ok(affected)
+    }
+
+    /// Learn categories of `pairs` keyed with `filter`, returns number of
+    /// learned pairs
+    pub fn learn<F>(&mut self, pairs: &[(String, String)], filter: F) -> Result<usize, UserError>
+    where
+        F: Fn(&str) -> Cow<'_, str>,
+    {
+        let learned = categories::learn(pairs, filter, &mut self.catmap, &self.accounts);
+        self.save_catmap()?;
+        Ok(learned)
+    }
+
+    /// Stored value of the setting `key`
+    pub fn setting(&self, key: &str) -> Option<String> {
+        self.db.setting(key).unwrap_or_else(|err| {
+            log::error!("Can't read setting {} due to {:?}", key, err);
+            None
+        })
+    }
+
+    pub fn set_setting(&mut self, key: &str, value: &str) -> Result<(), UserError> {
+        self.db.set_setting(key, value).map_err(UserError::DbError)
+    }
+
+    /// Key the setting `key` of household `member` is stored under, the
+    /// owner's settings keep the plain keys
+    fn member_key(&self, member: i64, key: &str) -> String {
+        if member == self.uid {
+            key.to_string()
+        } else {
+            format!("{}:{}", key, member)
+        }
+    }
+
+    /// Stored value of the setting `key` of household `member`
+    pub fn member_setting(&self, member: i64, key: &str) -> Option<String> {
+        self.setting(&self.member_key(member, key))
+    }
+
+    /// Settings are personal, members sharing the database don't overwrite
+    /// each other's ones
+    pub fn set_member_setting(
+        &mut self,
+        member: i64,
+        key: &str,
+        value: &str,
+    ) -> Result<(), UserError> {
+        let key = self.member_key(member, key);
+        self.set_setting(&key, value)
+    }
+
+    /// Forget the settings of household `member` leaving for good
+    pub fn remove_member_settings(&mut self, member: i64) -> Result<(), UserError> {
+        for key in [ACCOUNT_SETTING, ACCOUNT_TYPE_SETTING, MEMO_SETTING] {
+            let key = self.member_key(member, key);
+            self.db.remove_setting(&key).map_err(UserError::DbError)?;
+        }
         Ok(())
     }
 
+    /// Settings of household `member`
+    pub fn settings(&self, member: i64) -> Settings {
+        let default = Settings::default();
+        Settings {
+            account: self.member_setting(member, ACCOUNT_SETTING),
+            account_type: self
+                .member_setting(member, ACCOUNT_TYPE_SETTING)
+                .and_then(|t| t.parse().ok())
+                .unwrap_or(default.account_type),
+            memo: self
+                .member_setting(member, MEMO_SETTING)
+                .unwrap_or(default.memo),
+        }
+    }
+
+    /// Postable accounts purchases can be paid from
+    pub fn source_accounts(&self) -> Vec<String> {
+        self.account_tree
+            .sources()
+            .filter(|a| a.is_postable())
+            .map(|a| a.name.clone())
+            .collect()
+    }
+
+    /// QIF type of the imported account `name`
+    fn account_type(&self, name: &str) -> Option<AccountType> {
+        self.account_tree.get(name).and_then(|a| a.kind.qif_type())
+    }
+
+    /// Make `name` the default payment account of `member`, the type is
+    /// taken from the imported accounts unless given
+    pub fn set_payment_account(
+        &mut self,
+        member: i64,
+        name: &str,
+        account_type: Option<AccountType>,
+    ) -> Result<(), UserError> {
+        let account_type = account_type
+            .or_else(|| self.account_type(name))
+            .unwrap_or(Settings::default().account_type);
+        self.set_member_setting(member, ACCOUNT_SETTING, name)?;
+        self.set_member_setting(
+            member,
+            ACCOUNT_TYPE_SETTING,
+            account_type_name(account_type),
+        )
+    }
+
+    /// QIF account the purchases of `member` are paid from, `name` overrides
+    /// the default one. None if neither is set.
+    pub fn payment_account(&self, member: i64, name: Option<&str>) -> Option<Account> {
+        let settings = self.settings(member);
+        let (name, account_type) = match name {
+            Some(name) => (
+                name.to_string(),
+                self.account_type(name).unwrap_or(settings.account_type),
+            ),
+            None => (settings.account?, settings.account_type),
+        };
+        Some(
+            Account::new()
+                .name(&name)
+                .account_type(account_type)
+                .build(),
+        )
+    }
+
+    /// Remove the database with its backups, the user is not saved back
+    pub fn erase(mut self) -> Result<(), UserError> {
+        self.erased = true;
+        let path = self.path.clone();
+        // The store must be closed before the files are removed
+        drop(self);
+
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let backup = format!("{}.v", name);
+        if let Some(dir) = path.parent() {
+            for entry in fs::read_dir(dir).map_err(StoreError::Io)? {
+                let entry = entry.map_err(StoreError::Io)?;
+                let other = entry.file_name().to_string_lossy().into_owned();
+                if other.starts_with(&backup) && other.ends_with(".bak") {
+                    fs::remove_file(entry.path()).map_err(StoreError::Io)?;
+                }
+            }
+        }
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(StoreError::Io(e).into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the receipt with `id` was converted before
+    pub fn is_processed(&self, id: &str) -> bool {
+        self.db.is_processed(id).unwrap_or_else(|err| {
+            log::error!("Can't check receipt {} due to {:?}", id, err);
+            false
+        })
+    }
+
+    /// Remember the receipt with `id` as converted
+    pub fn mark_processed(&mut self, id: &str) -> Result<(), UserError> {
+        self.db.mark_processed(id).map_err(UserError::DbError)
+    }
+
     pub fn new_account(&mut self, acc: String) {
+        if self.account_tree.get(&acc).is_none() {
+            self.account_tree
+                .insert(AccountInfo::new(AccountKind::Expense, &acc));
+        }
         self.accounts.insert(acc);
     }
+
+    /// Export learned categories to share them with other users
+    pub fn export_knowledge(&self) -> Knowledge {
+        Knowledge::from_catmap(&self.catmap)
+    }
+
+    /// Merge categories learned by someone else, returns number of items
+    pub fn import_knowledge(
+        &mut self,
+        knowledge: &Knowledge,
+        mode: MergeMode,
+    ) -> Result<usize, UserError> {
+        let merged = knowledge.merge_into(&mut self.catmap, mode);
+        self.save_catmap()?;
+        Ok(merged)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::fs::create_dir_all;
+    use std::fs::{self, create_dir_all};
 
     fn setup(db_suffix: &str) -> Result<User, Box<dyn std::error::Error>> {
         create_dir_all(DEFAULT_DB_PATH)?;
 
         let temp_db_path = format!("{}test_user_{}.db", DEFAULT_DB_PATH, db_suffix);
-        let user = User::new(123, &Some(temp_db_path));
+        let user = User::new(123, &Some(temp_db_path), &FilterConfig::default())?;
         Ok(user)
     }
 
@@ -152,6 +530,274 @@ mod tests {
         assert!(user.accounts.contains("account"));
     }
 
+    #[test]
+    fn test_account_tree() {
+        let mut user = setup("account_tree").expect("Failed to set up user for account tree");
+
+        user.account_tree(
+            [
+                AccountInfo {
+                    placeholder: true,
+                    ..AccountInfo::new(AccountKind::Expense, "Expenses")
+                },
+                AccountInfo::new(AccountKind::Expense, "Expenses:Food"),
+                AccountInfo::new(AccountKind::Bank, "Assets:Bank"),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        assert_eq!(user.accounts, HashSet::from(["Expenses:Food".to_string()]));
+        user.save_data().expect("Failed to save data");
+
+        let reloaded = setup("account_tree").expect("Failed to reload user");
+        assert_eq!(reloaded.account_tree.sources().count(), 1);
+    }
+
+    #[test]
+    fn test_knowledge_exchange() {
+        let mut user = setup("knowledge_src").expect("Failed to set up source user");
+        user.catmap = Trie::new();
+        crate::categories::assign_category("milk", "Expenses:Dairy", &mut user.catmap);
+        let knowledge = user.export_knowledge();
+
+        let mut other = setup("knowledge_dst").expect("Failed to set up target user");
+        other.catmap = Trie::new();
+        assert_eq!(
+            other.import_knowledge(&knowledge, MergeMode::Sum).unwrap(),
+            1
+        );
+        other.import_knowledge(&knowledge, MergeMode::Sum).unwrap();
+        assert_eq!(other.catmap.get("milk").unwrap()[0].hits(), 2);
+    }
+
+    #[test]
+    fn test_processed_receipts() {
+        let mut user = setup("receipts").expect("Failed to set up user for receipts");
+        user.mark_processed("fn:fd:fp")
+            .expect("Failed to mark receipt");
+        drop(user);
+
+        let user = setup("receipts").expect("Failed to reload user for receipts");
+        assert!(user.is_processed("fn:fd:fp"));
+        assert!(!user.is_processed("fn:fd:other"));
+    }
+
+    #[test]
+    fn test_load_errors() {
+        create_dir_all(DEFAULT_DB_PATH).unwrap();
+        let path = format!("{}test_user_corrupt.db", DEFAULT_DB_PATH);
+        let _ = fs::remove_file(&path);
+        assert!(matches!(
+            User::load(123, &Some(path.clone()), &FilterConfig::default()),
+            Err(LoadError::NotFound(_))
+        ));
+
+        fs::write(&path, "{not json").unwrap();
+        let quarantine = match User::new(123, &Some(path.clone()), &FilterConfig::default()) {
+            Err(LoadError::Corrupt { quarantine, .. }) => quarantine,
+            other => panic!("Corrupt database loaded: {:?}", other),
+        };
+        assert_eq!(fs::read_to_string(&quarantine).unwrap(), "{not json");
+        assert!(!Path::new(&path).exists());
+        fs::remove_file(quarantine).unwrap();
+
+        // The quarantined database is replaced with a new one
+        let user = User::new(123, &Some(path.clone()), &FilterConfig::default())
+            .expect("Failed to start over");
+        assert_eq!(user.catmap, Trie::new());
+        drop(user);
+        fs::remove_file(&path).unwrap();
+
+        assert!(matches!(
+            User::new(
+                123,
+                &Some("/nonexistent/dir/test.db".to_string()),
+                &FilterConfig::default()
+            ),
+            Err(LoadError::Io { .. })
+        ));
+    }
+
+    #[test]
+    fn test_stored_stats() {
+        use pickledb::{PickleDb, PickleDbDumpPolicy, SerializationMethod};
+
+        create_dir_all(DEFAULT_DB_PATH).unwrap();
+        let path = format!("{}test_user_stats.db", DEFAULT_DB_PATH);
+        let mut catmap = CatStats::new();
+        categories::assign_category("milk", "Expenses:Dairy", &mut catmap);
+        let mut db = PickleDb::new(
+            &path,
+            PickleDbDumpPolicy::AutoDump,
+            SerializationMethod::Json,
+        );
+        db.set("catmap", &catmap).unwrap();
+        db.set("accounts", &vec!["Expenses:Dairy".to_string()])
+            .unwrap();
+        drop(db);
+        let original = fs::read(&path).unwrap();
+
+        // Old database is neither migrated nor backed up
+        assert_eq!(
+            User::stored_stats(123, &Some(path.clone())).unwrap(),
+            (1, 1)
+        );
+        assert_eq!(fs::read(&path).unwrap(), original);
+        assert!(!store::backup_path(Path::new(&path), 1).exists());
+
+        // Corrupt one stays in place
+        fs::write(&path, "{not json").unwrap();
+        assert!(matches!(
+            User::stored_stats(123, &Some(path.clone())),
+            Err(LoadError::Io { .. })
+        ));
+        assert!(Path::new(&path).exists());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_settings_and_erase() {
+        let mut user = setup("erase").expect("Failed to set up user for erasing");
+        user.set_setting("memo", "Groceries")
+            .expect("Failed to store setting");
+        user.mark_processed("fn:fd:fp")
+            .expect("Failed to mark receipt");
+        drop(user);
+
+        let user = setup("erase").expect("Failed to reload user for erasing");
+        assert_eq!(user.setting("memo").as_deref(), Some("Groceries"));
+        assert_eq!(user.setting("account"), None);
+        user.erase().expect("Failed to erase user");
+        let path = format!("{}test_user_erase.db", DEFAULT_DB_PATH);
+        assert!(!Path::new(&path).exists());
+
+        let user = setup("erase").expect("Failed to start over");
+        assert!(!user.is_processed("fn:fd:fp"));
+        assert_eq!(user.setting("memo"), None);
+    }
+
+    #[test]
+    fn test_payment_account() {
+        let mut user = setup("payment").expect("Failed to set up user for payment");
+        user.account_tree(
+            [
+                AccountInfo::new(AccountKind::Credit, "Liabilities:Card"),
+                AccountInfo::new(AccountKind::Cash, "Assets:Wallet"),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        assert_eq!(
+            user.source_accounts(),
+            vec!["Assets:Wallet", "Liabilities:Card"]
+        );
+
+        user.set_payment_account(123, "Liabilities:Card", None)
+            .expect("Failed to set account");
+        user.set_member_setting(123, MEMO_SETTING, "Groceries")
+            .expect("Failed to set memo");
+        drop(user);
+
+        let user = setup("payment").expect("Failed to reload user for payment");
+        assert_eq!(
+            user.settings(123),
+            Settings {
+                account: Some("Liabilities:Card".to_string()),
+                account_type: AccountType::CreditCard,
+                memo: "Groceries".to_string(),
+            }
+        );
+        assert_eq!(
+            user.payment_account(123, None).unwrap().to_string(),
+            "!Account\nNLiabilities:Card\nTCCard\n^\n"
+        );
+        assert_eq!(
+            user.payment_account(123, Some("Assets:Wallet"))
+                .unwrap()
+                .to_string(),
+            "!Account\nNAssets:Wallet\nTCash\n^\n"
+        );
+        user.erase().expect("Failed to erase user");
+    }
+
+    #[test]
+    fn test_member_settings() {
+        let mut user = setup("members").expect("Failed to set up household");
+        user.account_tree(
+            [
+                AccountInfo::new(AccountKind::Credit, "Liabilities:Card"),
+                AccountInfo::new(AccountKind::Cash, "Assets:Wallet"),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        // The owner and a member share the database of 123
+        user.set_payment_account(123, "Liabilities:Card", None)
+            .expect("Failed to set owner account");
+        user.set_member_setting(123, MEMO_SETTING, "Groceries")
+            .expect("Failed to set owner memo");
+        user.set_payment_account(456, "Assets:Wallet", None)
+            .expect("Failed to set member account");
+        user.set_member_setting(456, MEMO_SETTING, "Lunch")
+            .expect("Failed to set member memo");
+        drop(user);
+
+        let user = setup("members").expect("Failed to reload household");
+        assert_eq!(
+            user.payment_account(123, None).unwrap().to_string(),
+            "!Account\nNLiabilities:Card\nTCCard\n^\n"
+        );
+        assert_eq!(user.settings(123).memo, "Groceries");
+        assert_eq!(
+            user.payment_account(456, None).unwrap().to_string(),
+            "!Account\nNAssets:Wallet\nTCash\n^\n"
+        );
+        assert_eq!(user.settings(456).memo, "Lunch");
+        // Members without own settings don't inherit the owner's ones
+        assert_eq!(user.settings(789), Settings::default());
+
+        let mut user = user;
+        user.remove_member_settings(456)
+            .expect("Failed to remove member settings");
+        assert_eq!(user.settings(456), Settings::default());
+        assert_eq!(user.settings(123).memo, "Groceries");
+        user.erase().expect("Failed to erase household");
+    }
+
+    #[test]
+    fn test_concurrent_sessions() {
+        let path = format!("{}test_user_sessions.db", DEFAULT_DB_PATH);
+        let _ = fs::remove_file(&path);
+        let mut first = setup("sessions").expect("Failed to set up first session");
+        let mut second = setup("sessions").expect("Failed to set up second session");
+
+        first.assign_category("milk", "Expenses:Dairy").unwrap();
+        second.assign_category("milk", "Expenses:Dairy").unwrap();
+        second.assign_category("bread", "Expenses:Food").unwrap();
+        drop(second);
+        // Neither the last save nor a crash loses the other session learning
+        drop(first);
+        let user = setup("sessions").expect("Failed to reload sessions");
+        assert_eq!(user.catmap.get("milk").unwrap()[0].hits(), 2);
+        assert!(user.catmap.get("bread").is_some());
+        drop(user);
+
+        let mut user = setup("sessions").expect("Failed to reload for rename");
+        assert_eq!(
+            user.rename_category("Expenses:Food", "Expenses:Bakery")
+                .unwrap(),
+            vec!["bread"]
+        );
+        std::mem::forget(user);
+        let user = setup("sessions").expect("Failed to reload after rename");
+        assert_eq!(
+            categories::get_top_category("bread", &user.catmap),
+            Some("Expenses:Bakery")
+        );
+        drop(user);
+        fs::remove_file(&path).unwrap();
+    }
+
     #[test]
     fn test_saving_data() {
         let mut user = setup("save_data").expect("Failed to set up user for saving data");